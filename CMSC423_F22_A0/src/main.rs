@@ -1,72 +1,1022 @@
 use std::env;
-use std::fs::File;
-use std::io::{prelude::*, BufReader};
+use std::fs::{self, File};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 use json::JsonValue;
+use flate2::read::MultiGzDecoder;
+
+// Offset of the Phred+33 quality encoding used by Illumina 1.8+
+const PHRED_OFFSET: u8 = 33;
+// Order of the symbols tallied by Composition
+const BASES: [&str; 6] = ["A", "C", "G", "T", "N", "other"];
+// Default number of bins in the length histogram
+const HIST_BINS: u32 = 10;
+// Leading bytes of every gzip (and bgzip) member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// IUPAC nucleotide codes plus gap and stop symbols accepted by --validate
+const VALID_SYMBOLS: &[u8] = b"ACGTUNRYKMSWBDHV-*";
+// Diagnostics printed before --validate summarises the rest
+const MAX_DIAGNOSTICS: usize = 100;
+// Columns of the batch TSV report, taken from each file's JSON stats
+const TSV_COLUMNS: [&str; 11] = ["num_records", "tot_len", "min_len", "max_len", "mean_len",
+    "n50", "n90", "l50", "gc_frac", "mean_qual", "q30_frac"];
+// Largest k whose 2-bit encoding fits in a u64
+const MAX_K: usize = 31;
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    inputs: Vec<String>,
+    records_tsv: Option<String>,
+    // Expected genome size used for NG50
+    genome_size: Option<u64>,
+    hist_bins: u32,
+    validate: bool,
+    // Batch report format, "json" or "tsv"
+    format: String,
+    threads: usize,
+    // k for the optional k-mer spectrum
+    kmer: Option<usize>,
+}
+
+// One FASTA or FASTQ record; qual is only set for FASTQ
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Record {
+    name: String,
+    seq: String,
+    qual: Option<String>,
+}
+
+// Streams records out of a FASTA file. Blank lines are skipped, and sequence
+// before the first header is returned as a record with an empty name
+pub struct FastaRecords<R: BufRead> {
+    lines: io::Lines<R>,
+    // Header of the next record, once it has been read
+    next_name: Option<String>,
+    done: bool,
+}
+
+// Streams four-line records (header, sequence, '+', qualities) out of a FASTQ file
+pub struct FastqRecords<R: BufRead> {
+    lines: io::Lines<R>,
+}
+
+// Running length aggregates over all records
+#[derive(Debug, Clone)]
+pub struct LenStats {
+    tot_len: u64,
+    min_len: u32,
+    max_len: u32,
+    num_records: u32,
+    // Every record length, kept for N50-style metrics and the histogram
+    lengths: Vec<u32>,
+}
+
+// Running quality aggregates over all FASTQ records
+#[derive(Debug, Clone, Default)]
+pub struct QualStats {
+    num_bases: u64,
+    tot_qual: u64,
+    q20_bases: u64,
+    q30_bases: u64,
+    // Sum of qualities and number of bases seen at each read position
+    pos_tot: Vec<u64>,
+    pos_count: Vec<u64>,
+}
+
+// Counts of A, C, G, T, N and any other symbol, case-insensitive
+#[derive(Debug, Clone, Default)]
+pub struct Composition {
+    counts: [u64; 6],
+}
+
+// Canonical k-mer counts, fed sequence a line at a time
+#[derive(Debug, Clone, Default)]
+pub struct KmerCounter {
+    k: usize,
+    counts: HashMap<u64, u32>,
+    // 2-bit encodings of the current window and of its reverse complement
+    fwd: u64,
+    rev: u64,
+    // Consecutive A/C/G/T bases in the current window
+    filled: usize,
+}
+
+// Everything gathered while reading one input file
+#[derive(Debug, Default)]
+pub struct Summary {
+    len: LenStats,
+    qual: Option<QualStats>,
+    comp: Composition,
+    kmers: Option<KmerCounter>,
+    // Optional per-record report (name, length, GC, N count)
+    record_tsv: Option<BufWriter<File>>,
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let path = &args[1];
-    let file = File::open(path).expect("Unable to open file");
-    let reader = BufReader::new(file);
-    let mut firstrun = true;
-    let mut tot_len = 0;
-    let mut min_len = u32::MAX;
-    let mut max_len = 0;
-    let mut num_records = 0;
-    let mut agg_length = 0;
-    let mut stats = json::parse(r#"
-        {
-            "min_len": 0,
-            "max_len": 0,
-            "mean_len": 0,
-            "tot_len": 0,
-            "num_records": 0
-        }
-    "#).unwrap();
-
-    for line in reader.lines() {
-        if let Ok(curr) = line {
-            match curr.chars().next() {
-                Some(firstchar) => {
-                    if firstchar == '>' {
-                        if !firstrun {
-                            if agg_length > max_len {
-                                max_len = agg_length;
-                            }
-                            if agg_length < min_len {
-                                min_len = agg_length;
-                            }
-                        }
-                        num_records += 1;
-                        agg_length = 0;
-                    } else {
-                        firstrun = false;
-                        tot_len += curr.chars().count();
-                        agg_length = agg_length + curr.len() as u32;
+    let opts = parse_args(&args);
+
+    if opts.validate {
+        let mut failed = false;
+        for input in &opts.inputs {
+            let diagnostics = validate_fasta(open_input(input));
+            // Prefix diagnostics with the file name when checking several files
+            let prefix = if opts.inputs.len() > 1 { format!("{}: ", input) } else { String::new() };
+            for diag in diagnostics.iter().take(MAX_DIAGNOSTICS) {
+                println!("{}{}", prefix, diag);
+            }
+            if diagnostics.len() > MAX_DIAGNOSTICS {
+                println!("{}... {} more problems", prefix, diagnostics.len() - MAX_DIAGNOSTICS);
+            }
+            if diagnostics.is_empty() {
+                println!("{}: OK", input);
+            }
+            failed |= !diagnostics.is_empty();
+        }
+        if failed {
+            exit(1);
+        }
+        return;
+    }
+
+    if opts.inputs.len() == 1 && opts.format.is_empty() {
+        let mut summary = summarize(&opts.inputs[0], &opts);
+        println!("{:#}", summary.to_json(&opts));
+        return;
+    }
+
+    // Batch mode: one row per file plus an aggregate row
+    let mut summaries = summarize_all(&opts);
+    let mut total = Summary::default();
+    for summary in &summaries {
+        total.merge(summary);
+    }
+    let mut rows: Vec<(String, JsonValue)> = Vec::new();
+    for (input, summary) in opts.inputs.iter().zip(summaries.iter_mut()) {
+        rows.push((input.clone(), summary.to_json(&opts)));
+    }
+    rows.push(("total".to_string(), total.to_json(&opts)));
+
+    if opts.format == "tsv" {
+        println!("file\t{}", TSV_COLUMNS.join("\t"));
+        for (input, stats) in &rows {
+            let cols: Vec<String> = TSV_COLUMNS.iter().map(|col| {
+                if stats[*col].is_null() { String::new() } else { stats[*col].dump() }
+            }).collect();
+            println!("{}\t{}", input, cols.join("\t"));
+        }
+    } else {
+        let mut report = JsonValue::new_array();
+        for (input, stats) in rows {
+            let mut row = JsonValue::new_object();
+            row["file"] = JsonValue::from(input);
+            for (key, value) in stats.entries() {
+                row[key] = value.clone();
+            }
+            report.push(row).unwrap();
+        }
+        println!("{:#}", report);
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Options {
+    let mut opts = Options {
+        hist_bins: HIST_BINS,
+        // One worker per core unless --threads says otherwise
+        threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        ..Default::default()
+    };
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--records" if i + 1 < args.len() => {
+                opts.records_tsv = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--genome-size" if i + 1 < args.len() => {
+                opts.genome_size = Some(parse_num(&args[i + 1]));
+                i += 1;
+            }
+            "--hist-bins" if i + 1 < args.len() => {
+                opts.hist_bins = parse_num(&args[i + 1]);
+                i += 1;
+            }
+            "--validate" => {
+                opts.validate = true;
+            }
+            "--list" if i + 1 < args.len() => {
+                // One path per line, blank lines and '#' comments ignored
+                let list = fs::read_to_string(&args[i + 1]).expect("Unable to open file");
+                for line in list.lines().map(str::trim) {
+                    if !line.is_empty() && !line.starts_with('#') {
+                        opts.inputs.push(line.to_string());
                     }
                 }
-                None => {
+                i += 1;
+            }
+            "--format" if i + 1 < args.len() && (args[i + 1] == "json" || args[i + 1] == "tsv") => {
+                opts.format = args[i + 1].clone();
+                i += 1;
+            }
+            "--kmer" if i + 1 < args.len() => {
+                let k: usize = parse_num(&args[i + 1]);
+                if k == 0 || k > MAX_K {
+                    println!("k must be between 1 and {}", MAX_K);
+                    exit(1);
+                }
+                opts.kmer = Some(k);
+                i += 1;
+            }
+            "--threads" if i + 1 < args.len() => {
+                opts.threads = parse_num::<usize>(&args[i + 1]).max(1);
+                i += 1;
+            }
+            arg if arg == "-" || !arg.starts_with("--") => {
+                opts.inputs.push(arg.to_string());
+            }
+            _ => {
+                println!("Invalid parameters");
+                exit(1);
+            }
+        }
+        i += 1;
+    }
+    if opts.inputs.is_empty() {
+        println!("Invalid parameters");
+        exit(1);
+    }
+    if opts.inputs.len() > 1 && opts.records_tsv.is_some() {
+        println!("--records only supports a single input file");
+        exit(1);
+    }
+    opts
+}
+
+// Reads one input file, FASTA or FASTQ, into a Summary
+pub fn summarize(input: &str, opts: &Options) -> Summary {
+    let mut reader = open_input(input);
+    let mut summary = Summary {
+        kmers: opts.kmer.map(KmerCounter::new),
+        ..Default::default()
+    };
+    if let Some(path) = &opts.records_tsv {
+        let mut out = BufWriter::new(File::create(path).expect("Unable to open file"));
+        if let Err(e) = writeln!(out, "name\tlength\tgc\tn_count") {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+        summary.record_tsv = Some(out);
+    }
+
+    // FASTQ records start with '@', FASTA records with '>'
+    let is_fastq = match reader.fill_buf() {
+        Ok(buf) => buf.first() == Some(&b'@'),
+        Err(_) => false,
+    };
+    if is_fastq {
+        for record in FastqRecords::new(reader) {
+            summary.add_record(&record);
+        }
+    } else {
+        for record in FastaRecords::new(reader) {
+            summary.add_record(&record);
+        }
+    }
+    summary
+}
+
+// Summarizes every input, spreading files across opts.threads workers
+pub fn summarize_all(opts: &Options) -> Vec<Summary> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Summary>>> = Mutex::new((0..opts.inputs.len()).map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..opts.threads.min(opts.inputs.len()) {
+            scope.spawn(|| loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                if idx >= opts.inputs.len() {
                     break;
                 }
+                let summary = summarize(&opts.inputs[idx], opts);
+                results.lock().unwrap()[idx] = Some(summary);
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().map(|s| s.unwrap()).collect()
+}
+
+// Opens a path, or stdin for "-", decompressing gzip/bgzip input transparently
+pub fn open_input(path: &str) -> Box<dyn BufRead> {
+    let mut reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(path).expect("Unable to open file")))
+    };
+    let is_gzip = match reader.fill_buf() {
+        Ok(buf) => buf.starts_with(&GZIP_MAGIC),
+        Err(_) => false,
+    };
+    if is_gzip {
+        // bgzip files are a series of gzip members, so all of them must be read
+        reader = Box::new(BufReader::new(MultiGzDecoder::new(reader)));
+    }
+    reader
+}
+
+pub fn parse_num<T: std::str::FromStr>(arg: &str) -> T {
+    match arg.parse() {
+        Ok(num) => num,
+        Err(_) => {
+            println!("Invalid parameters");
+            exit(1);
+        }
+    }
+}
+
+
+// Checks a FASTA file line by line, returning "line N: problem" diagnostics
+pub fn validate_fasta(mut reader: impl BufRead) -> Vec<String> {
+    let mut diagnostics: Vec<String> = Vec::new();
+    // Record name -> line of its first header
+    let mut names: HashMap<String, usize> = HashMap::new();
+    let mut line = String::new();
+    let mut line_num = 0;
+    // Line of the current record's header, and whether it has any sequence
+    let mut header_line: Option<usize> = None;
+    let mut has_seq = false;
+    let mut blank_line: Option<usize> = None;
+    let mut crlf_lines = 0;
+
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                diagnostics.push(format!("line {}: unreadable input ({})", line_num + 1, e));
+                break;
+            }
+        }
+        line_num += 1;
+        if line.ends_with("\r\n") {
+            if crlf_lines == 0 {
+                diagnostics.push(format!("line {}: CRLF line ending", line_num));
+            }
+            crlf_lines += 1;
+        }
+        let curr = line.trim_end_matches(['\r', '\n']);
+        if curr.is_empty() {
+            blank_line.get_or_insert(line_num);
+            continue;
+        }
+        // A blank line is only a problem if more content follows it
+        if let Some(blank) = blank_line.take() {
+            diagnostics.push(format!("line {}: blank line inside file", blank));
+        }
+        if let Some(header) = curr.strip_prefix('>') {
+            if let Some(prev) = header_line {
+                if !has_seq {
+                    diagnostics.push(format!("line {}: record has no sequence", prev));
+                }
+            }
+            let name = header.split_whitespace().next().unwrap_or("");
+            if name.is_empty() {
+                diagnostics.push(format!("line {}: header has no name", line_num));
+            } else if let Some(first) = names.get(name) {
+                diagnostics.push(format!("line {}: duplicate record name '{}' (first seen on line {})", line_num, name, first));
+            } else {
+                names.insert(name.to_string(), line_num);
             }
-            
+            header_line = Some(line_num);
+            has_seq = false;
+        } else {
+            if header_line.is_none() {
+                diagnostics.push(format!("line {}: sequence before first header", line_num));
+                // Only report the header-less block once
+                header_line = Some(line_num);
+            }
+            has_seq = true;
+            if let Some((col, c)) = curr.char_indices().find(|(_, c)| !c.is_ascii() || !VALID_SYMBOLS.contains(&(*c as u8).to_ascii_uppercase())) {
+                diagnostics.push(format!("line {}: illegal character '{}' at column {}", line_num, c.escape_default(), col + 1));
+            }
+        }
+    }
+    match header_line {
+        Some(prev) if !has_seq => diagnostics.push(format!("line {}: record has no sequence", prev)),
+        None => diagnostics.push("line 1: no FASTA records found".to_string()),
+        _ => {}
+    }
+    if crlf_lines > 1 {
+        diagnostics.push(format!("{} lines in total end in CRLF", crlf_lines));
+    }
+    diagnostics
+}
+
+
+// Unreadable input (I/O errors, bad gzip data, invalid UTF-8) ends the run
+// rather than the file, so it never yields statistics over part of it
+fn line_or_exit(line: io::Result<String>) -> String {
+    match line {
+        Ok(line) => line,
+        Err(e) => {
+            eprintln!("Unable to read input: {}", e);
+            exit(1);
         }
     }
-    // Checking stats on final read
+}
 
-    if agg_length > max_len {
-        max_len = agg_length;
+impl<R: BufRead> FastaRecords<R> {
+    pub fn new(reader: R) -> FastaRecords<R> {
+        FastaRecords {
+            lines: reader.lines(),
+            next_name: None,
+            done: false,
+        }
     }
-    if agg_length < min_len {
-        min_len = agg_length;
+}
+
+impl<R: BufRead> Iterator for FastaRecords<R> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        if self.done {
+            return None;
+        }
+        // Whether anything (header or sequence) belongs to this record
+        let mut started = self.next_name.is_some();
+        let mut record = Record {
+            name: self.next_name.take().unwrap_or_default(),
+            ..Default::default()
+        };
+        for curr in self.lines.by_ref().map(line_or_exit) {
+            if let Some(header) = curr.strip_prefix('>') {
+                if started {
+                    self.next_name = Some(header.to_string());
+                    return Some(record);
+                }
+                record.name = header.to_string();
+                started = true;
+            } else if !curr.is_empty() {
+                record.seq.push_str(curr.trim_end());
+                started = true;
+            }
+        }
+        self.done = true;
+        if started { Some(record) } else { None }
+    }
+}
+
+impl<R: BufRead> FastqRecords<R> {
+    pub fn new(reader: R) -> FastqRecords<R> {
+        FastqRecords {
+            lines: reader.lines(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for FastqRecords<R> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let mut lines = self.lines.by_ref().map(line_or_exit);
+        let header = lines.by_ref().find(|line| !line.is_empty())?;
+        if !header.starts_with('@') {
+            eprintln!("Malformed FASTQ header: {}", header);
+            exit(1);
+        }
+        let seq = lines.next().unwrap_or_default();
+        let plus = lines.next().unwrap_or_default();
+        let qual = lines.next().unwrap_or_default();
+        if !plus.starts_with('+') || qual.len() != seq.len() {
+            eprintln!("Malformed FASTQ record: {}", header);
+            exit(1);
+        }
+        Some(Record {
+            name: header[1..].to_string(),
+            seq,
+            qual: Some(qual),
+        })
+    }
+}
+
+impl Summary {
+    // Folds one record into the running totals
+    pub fn add_record(&mut self, record: &Record) {
+        let len = record.seq.len() as u32;
+        let mut comp = Composition::default();
+        comp.add(&record.seq);
+        self.len.add(len);
+        self.comp.merge(&comp);
+        if let Some(qual) = &record.qual {
+            self.qual.get_or_insert_with(QualStats::default).add(qual);
+        }
+        if let Some(kmers) = &mut self.kmers {
+            kmers.add(&record.seq);
+            kmers.end_record();
+        }
+        if let Some(out) = &mut self.record_tsv {
+            if let Err(e) = writeln!(out, "{}\t{}\t{:.4}\t{}", record.name, len, comp.gc_frac(), comp.counts[4]) {
+                eprintln!("Couldn't write to file: {}", e);
+            }
+        }
+    }
+
+    // Adds another file's totals; per-record reports are not carried over
+    pub fn merge(&mut self, other: &Summary) {
+        self.len.merge(&other.len);
+        self.comp.merge(&other.comp);
+        if let Some(other_qual) = &other.qual {
+            self.qual.get_or_insert_with(QualStats::default).merge(other_qual);
+        }
+        if let Some(other_kmers) = &other.kmers {
+            self.kmers.get_or_insert_with(|| KmerCounter::new(other_kmers.k)).merge(other_kmers);
+        }
+    }
+
+    pub fn to_json(&mut self, opts: &Options) -> JsonValue {
+        let mut stats = json::parse(r#"
+            {
+                "min_len": 0,
+                "max_len": 0,
+                "mean_len": 0,
+                "tot_len": 0,
+                "num_records": 0
+            }
+        "#).unwrap();
+        self.len.lengths.sort_unstable_by(|a, b| b.cmp(a));
+        stats["tot_len"] = JsonValue::from(self.len.tot_len);
+        stats["num_records"] = JsonValue::from(self.len.num_records);
+        if self.len.num_records != 0 {
+            stats["max_len"] = JsonValue::from(self.len.max_len);
+            stats["min_len"] = JsonValue::from(self.len.min_len);
+            stats["mean_len"] = JsonValue::from(self.len.mean());
+            stats["median_len"] = JsonValue::from(self.len.median());
+            stats["sd_len"] = JsonValue::from(self.len.sd());
+        }
+        stats["gc_frac"] = JsonValue::from(self.comp.gc_frac());
+        for (base, count) in BASES.iter().zip(self.comp.counts.iter()) {
+            stats["base_counts"][*base] = JsonValue::from(*count);
+        }
+        if let Some(qual) = &self.qual {
+            stats["mean_qual"] = JsonValue::from(qual.mean_qual());
+            stats["q20_frac"] = JsonValue::from(qual.frac(qual.q20_bases));
+            stats["q30_frac"] = JsonValue::from(qual.frac(qual.q30_bases));
+            stats["qual_profile"] = JsonValue::from(qual.profile());
+        }
+        self.len.add_assembly_json(&mut stats, opts.genome_size, opts.hist_bins);
+        if let Some(kmers) = &self.kmers {
+            stats["kmer"] = kmers.to_json();
+        }
+        stats
+    }
+}
+
+impl Default for LenStats {
+    fn default() -> LenStats {
+        LenStats {
+            tot_len: 0,
+            min_len: u32::MAX,
+            max_len: 0,
+            num_records: 0,
+            lengths: Vec::new(),
+        }
+    }
+}
+
+impl LenStats {
+    pub fn add(&mut self, len: u32) {
+        self.num_records += 1;
+        self.tot_len += len as u64;
+        self.min_len = self.min_len.min(len);
+        self.max_len = self.max_len.max(len);
+        self.lengths.push(len);
+    }
+
+    pub fn merge(&mut self, other: &LenStats) {
+        self.tot_len += other.tot_len;
+        self.num_records += other.num_records;
+        self.min_len = self.min_len.min(other.min_len);
+        self.max_len = self.max_len.max(other.max_len);
+        self.lengths.extend_from_slice(&other.lengths);
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.tot_len as f64 / self.num_records as f64
+    }
+
+    // Expects lengths sorted (in either direction)
+    pub fn median(&self) -> f64 {
+        let n = self.lengths.len();
+        if n % 2 == 1 {
+            self.lengths[n / 2] as f64
+        } else {
+            (self.lengths[n / 2 - 1] as f64 + self.lengths[n / 2] as f64) / 2.0
+        }
+    }
+
+    // Sample standard deviation, 0 for fewer than two records
+    pub fn sd(&self) -> f64 {
+        if self.lengths.len() < 2 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        let var: f64 = self.lengths.iter().map(|len| (*len as f64 - mean).powi(2)).sum();
+        (var / (self.lengths.len() - 1) as f64).sqrt()
+    }
+
+    // Adds N50/N90/L50, NG50 (given a genome size) and the length histogram
+    pub fn add_assembly_json(&mut self, stats: &mut JsonValue, genome_size: Option<u64>, bins: u32) {
+        self.lengths.sort_unstable_by(|a, b| b.cmp(a));
+        let total: u64 = self.lengths.iter().map(|l| *l as u64).sum();
+        let n50 = self.nx(total, 50);
+        let n90 = self.nx(total, 90);
+        stats["n50"] = JsonValue::from(n50.map_or(0, |(len, _)| len));
+        stats["n90"] = JsonValue::from(n90.map_or(0, |(len, _)| len));
+        stats["l50"] = JsonValue::from(n50.map_or(0, |(_, count)| count));
+        if let Some(size) = genome_size {
+            // NG50 is undefined when the records cover less than half the genome
+            stats["ng50"] = match self.nx(size, 50) {
+                Some((len, _)) => JsonValue::from(len),
+                None => JsonValue::Null,
+            };
+        }
+        stats["len_hist"] = JsonValue::new_array();
+        for (start, end, count) in self.histogram(bins) {
+            let mut bin = JsonValue::new_object();
+            bin["start"] = JsonValue::from(start);
+            bin["end"] = JsonValue::from(end);
+            bin["count"] = JsonValue::from(count);
+            stats["len_hist"].push(bin).unwrap();
+        }
+    }
+
+    // Length and count of the records needed to reach x% of total bases.
+    // Expects lengths sorted in decreasing order
+    pub fn nx(&self, total: u64, x: u64) -> Option<(u32, u32)> {
+        let target = total * x;
+        let mut cumulative: u64 = 0;
+        for (count, len) in self.lengths.iter().enumerate() {
+            cumulative += *len as u64;
+            if cumulative * 100 >= target {
+                return Some((*len, count as u32 + 1));
+            }
+        }
+        None
+    }
+
+    // Equal-width bins (start, end inclusive, count) spanning min_len..=max_len
+    pub fn histogram(&self, bins: u32) -> Vec<(u32, u32, u32)> {
+        if self.lengths.is_empty() || bins == 0 {
+            return Vec::new();
+        }
+        let width = (self.max_len - self.min_len) / bins + 1;
+        let mut hist: Vec<(u32, u32, u32)> = (0..bins)
+            .map(|b| (self.min_len + b * width, self.min_len + (b + 1) * width - 1, 0))
+            .take_while(|(start, _, _)| *start <= self.max_len)
+            .collect();
+        for len in &self.lengths {
+            hist[((len - self.min_len) / width) as usize].2 += 1;
+        }
+        hist
+    }
+}
+
+impl QualStats {
+    pub fn add(&mut self, qual: &str) {
+        if qual.len() > self.pos_tot.len() {
+            self.pos_tot.resize(qual.len(), 0);
+            self.pos_count.resize(qual.len(), 0);
+        }
+        for (pos, c) in qual.bytes().enumerate() {
+            let q = c.saturating_sub(PHRED_OFFSET) as u64;
+            self.num_bases += 1;
+            self.tot_qual += q;
+            if q >= 20 {
+                self.q20_bases += 1;
+            }
+            if q >= 30 {
+                self.q30_bases += 1;
+            }
+            self.pos_tot[pos] += q;
+            self.pos_count[pos] += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &QualStats) {
+        self.num_bases += other.num_bases;
+        self.tot_qual += other.tot_qual;
+        self.q20_bases += other.q20_bases;
+        self.q30_bases += other.q30_bases;
+        if other.pos_tot.len() > self.pos_tot.len() {
+            self.pos_tot.resize(other.pos_tot.len(), 0);
+            self.pos_count.resize(other.pos_count.len(), 0);
+        }
+        for (pos, (tot, count)) in other.pos_tot.iter().zip(other.pos_count.iter()).enumerate() {
+            self.pos_tot[pos] += tot;
+            self.pos_count[pos] += count;
+        }
+    }
+
+    pub fn mean_qual(&self) -> f64 {
+        self.frac(self.tot_qual)
+    }
+
+    // Fraction of all bases, or 0 if no bases were seen
+    pub fn frac(&self, count: u64) -> f64 {
+        if self.num_bases == 0 {
+            return 0.0;
+        }
+        count as f64 / self.num_bases as f64
+    }
+
+    // Mean quality at each read position
+    pub fn profile(&self) -> Vec<f64> {
+        self.pos_tot.iter()
+            .zip(self.pos_count.iter())
+            .map(|(tot, count)| *tot as f64 / *count as f64)
+            .collect()
+    }
+}
+
+impl Composition {
+    pub fn add(&mut self, seq: &str) {
+        for c in seq.bytes() {
+            let idx = match c.to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                b'N' => 4,
+                _ => 5,
+            };
+            self.counts[idx] += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Composition) {
+        for (count, add) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += add;
+        }
+    }
+
+    // G+C over all called bases (A/C/G/T), ignoring N and other symbols
+    pub fn gc_frac(&self) -> f64 {
+        let called: u64 = self.counts[..4].iter().sum();
+        if called == 0 {
+            return 0.0;
+        }
+        (self.counts[1] + self.counts[2]) as f64 / called as f64
+    }
+}
+
+impl KmerCounter {
+    pub fn new(k: usize) -> KmerCounter {
+        KmerCounter {
+            k,
+            ..Default::default()
+        }
+    }
+
+    // Counts every k-mer in seq, continuing the window from the previous call
+    pub fn add(&mut self, seq: &str) {
+        let mask = (1u64 << (2 * self.k)) - 1;
+        let shift = 2 * (self.k - 1);
+        for c in seq.bytes() {
+            let code = match c.to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => {
+                    // k-mers containing N or other symbols are skipped
+                    self.filled = 0;
+                    continue;
+                }
+            };
+            self.fwd = ((self.fwd << 2) | code) & mask;
+            self.rev = (self.rev >> 2) | ((3 - code) << shift);
+            self.filled += 1;
+            if self.filled >= self.k {
+                *self.counts.entry(self.fwd.min(self.rev)).or_insert(0) += 1;
+            }
+        }
+    }
+
+    // k-mers never span two records
+    pub fn end_record(&mut self) {
+        self.filled = 0;
+    }
+
+    pub fn merge(&mut self, other: &KmerCounter) {
+        for (kmer, count) in &other.counts {
+            *self.counts.entry(*kmer).or_insert(0) += count;
+        }
+    }
+
+    // Number of distinct k-mers seen at each abundance
+    pub fn histogram(&self) -> BTreeMap<u32, u64> {
+        let mut hist: BTreeMap<u32, u64> = BTreeMap::new();
+        for count in self.counts.values() {
+            *hist.entry(*count).or_insert(0) += 1;
+        }
+        hist
+    }
+
+    // Homozygous peak depth, genome size and heterozygosity from the spectrum.
+    // The error peak is skipped by walking down to the first valley; the
+    // heterozygosity is a rough estimate from the k-mers near half the peak,
+    // each het SNP contributing about 2k of them
+    pub fn estimate(&self, hist: &BTreeMap<u32, u64>) -> Option<(u32, f64, f64)> {
+        let distinct_at = |a: u32| *hist.get(&a).unwrap_or(&0);
+        let max_abundance = *hist.keys().next_back()?;
+        let mut valley = 1;
+        while valley < max_abundance && distinct_at(valley + 1) < distinct_at(valley) {
+            valley += 1;
+        }
+        let (peak, _) = hist.range(valley..)
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))?;
+        if *peak <= valley {
+            return None;
+        }
+        let solid_kmers: u64 = hist.range(valley..).map(|(a, n)| *a as u64 * n).sum();
+        let genome_size = solid_kmers as f64 / *peak as f64;
+        let het_lo = (*peak as f64 * 0.375).round() as u32;
+        let het_hi = (*peak as f64 * 0.625).round() as u32;
+        // The window may lie entirely below the valley on low-coverage spectra
+        let het_lo = het_lo.max(valley);
+        let het_kmers: u64 = if het_lo > het_hi { 0 } else { hist.range(het_lo..=het_hi).map(|(_, n)| *n).sum() };
+        let het_rate = het_kmers as f64 / (2.0 * self.k as f64 * genome_size);
+        Some((*peak, genome_size, het_rate))
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let hist = self.histogram();
+        let mut stats = JsonValue::new_object();
+        stats["k"] = JsonValue::from(self.k);
+        stats["distinct"] = JsonValue::from(self.counts.len());
+        stats["total"] = JsonValue::from(self.counts.values().map(|c| *c as u64).sum::<u64>());
+        match self.estimate(&hist) {
+            Some((peak, genome_size, het_rate)) => {
+                stats["peak_depth"] = JsonValue::from(peak);
+                stats["genome_size_est"] = JsonValue::from(genome_size.round() as u64);
+                stats["heterozygosity_est"] = JsonValue::from(het_rate);
+            }
+            None => {
+                stats["peak_depth"] = JsonValue::Null;
+                stats["genome_size_est"] = JsonValue::Null;
+                stats["heterozygosity_est"] = JsonValue::Null;
+            }
+        }
+        stats["histogram"] = JsonValue::new_array();
+        for (abundance, distinct) in hist {
+            stats["histogram"].push(json::array![abundance, distinct]).unwrap();
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fasta(input: &str) -> Vec<(String, String)> {
+        FastaRecords::new(input.as_bytes())
+            .map(|r| (r.name, r.seq))
+            .collect()
+    }
+
+    fn rec(name: &str, seq: &str) -> (String, String) {
+        (name.to_string(), seq.to_string())
+    }
+
+    fn len_stats(input: &str) -> LenStats {
+        let mut stats = LenStats::default();
+        for record in FastaRecords::new(input.as_bytes()) {
+            stats.add(record.seq.len() as u32);
+        }
+        stats.lengths.sort_unstable();
+        stats
+    }
+
+    #[test]
+    fn empty_input_has_no_records() {
+        assert!(fasta("").is_empty());
+        assert!(fasta("\n\n").is_empty());
+    }
+
+    #[test]
+    fn single_record_spanning_lines() {
+        assert_eq!(fasta(">a desc\nACG\nTT\n"), vec![rec("a desc", "ACGTT")]);
+        // No trailing newline
+        assert_eq!(fasta(">a\nACG"), vec![rec("a", "ACG")]);
+    }
+
+    #[test]
+    fn empty_records_are_kept() {
+        assert_eq!(fasta(">a\n>b\nAC\n>c\n"), vec![rec("a", ""), rec("b", "AC"), rec("c", "")]);
+        assert_eq!(fasta(">\n>\nA\n"), vec![rec("", ""), rec("", "A")]);
+    }
+
+    #[test]
+    fn leading_headerless_block_is_a_record() {
+        assert_eq!(fasta("ACGT\n>a\nGG\n"), vec![rec("", "ACGT"), rec("a", "GG")]);
+    }
+
+    #[test]
+    fn blank_lines_and_crlf_do_not_end_input() {
+        assert_eq!(fasta(">a\r\nAC\r\n\r\nGT\r\n\n>b\nT\n"), vec![rec("a", "ACGT"), rec("b", "T")]);
+    }
+
+    #[test]
+    fn fastq_records() {
+        let records: Vec<Record> = FastqRecords::new("@r1\nACG\n+\nII#\n\n@r2\nT\n+r2\n5\n".as_bytes()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], Record {
+            name: "r2".to_string(),
+            seq: "T".to_string(),
+            qual: Some("5".to_string()),
+        });
+    }
+
+    #[test]
+    fn length_stats_are_exact() {
+        let stats = len_stats(">a\nACGT\n>b\n>c\nACG\n");
+        assert_eq!(stats.num_records, 3);
+        assert_eq!(stats.min_len, 0);
+        assert_eq!(stats.max_len, 4);
+        assert!((stats.mean() - 7.0 / 3.0).abs() < 1e-12);
+        assert_eq!(stats.median(), 3.0);
+        assert!((stats.sd() - (13.0f64 / 3.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn single_record_stats() {
+        let stats = len_stats(">a\nACGTA\n");
+        assert_eq!((stats.min_len, stats.max_len), (5, 5));
+        assert_eq!(stats.mean(), 5.0);
+        assert_eq!(stats.median(), 5.0);
+        assert_eq!(stats.sd(), 0.0);
+    }
+
+    #[test]
+    fn even_count_median_averages() {
+        assert_eq!(len_stats(">a\nA\n>b\nACGT\n").median(), 2.5);
+    }
+
+    fn kmer_counts(k: usize, seqs: &[&str]) -> BTreeMap<u64, u32> {
+        let mut counter = KmerCounter::new(k);
+        for seq in seqs {
+            counter.add(seq);
+            counter.end_record();
+        }
+        counter.counts.into_iter().collect()
+    }
+
+    #[test]
+    fn kmers_are_counted_canonically() {
+        // AC and GT are reverse complements (code 0b0001), CG is its own (0b0110)
+        assert_eq!(kmer_counts(2, &["ACGT"]), BTreeMap::from([(0b0001, 2), (0b0110, 1)]));
+        // A sequence and its reverse complement give the same counts
+        assert_eq!(kmer_counts(3, &["AACGTTG"]), kmer_counts(3, &["CAACGTT"]));
+        assert_eq!(kmer_counts(3, &["aacg"]), kmer_counts(3, &["AACG"]));
+    }
+
+    #[test]
+    fn kmers_skip_ambiguous_bases_and_record_ends() {
+        assert_eq!(kmer_counts(3, &["AANAA"]), BTreeMap::new());
+        assert_eq!(kmer_counts(3, &["AA", "AA"]), BTreeMap::new());
+        // The window continues across lines of the same record
+        let mut counter = KmerCounter::new(3);
+        counter.add("AA");
+        counter.add("AA");
+        assert_eq!(counter.counts, HashMap::from([(0, 2)]));
+    }
+
+    #[test]
+    fn estimate_from_spectrum() {
+        let hist = BTreeMap::from([(1, 1000), (2, 100), (3, 10), (10, 40), (19, 90), (20, 100), (21, 90)]);
+        let (peak, genome_size, het_rate) = KmerCounter::new(21).estimate(&hist).unwrap();
+        assert_eq!(peak, 20);
+        // (10 * 40 + 19 * 90 + 20 * 100 + 21 * 90) / 20 solid k-mers
+        assert_eq!(genome_size, 300.0);
+        assert!((het_rate - 40.0 / (2.0 * 21.0 * 300.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn estimate_needs_a_peak_past_the_valley() {
+        assert_eq!(KmerCounter::new(21).estimate(&BTreeMap::new()), None);
+        assert_eq!(KmerCounter::new(21).estimate(&BTreeMap::from([(1, 100), (2, 10)])), None);
     }
 
-    stats["tot_len"] = JsonValue::from(tot_len);
-    stats["max_len"] = JsonValue::from(max_len);
-    stats["min_len"] = JsonValue::from(min_len);
-    stats["num_records"] = JsonValue::from(num_records);
-    if num_records != 0 {
-        stats["mean_len"] = JsonValue::from(tot_len/num_records);
+    #[test]
+    fn estimate_with_valley_above_het_window() {
+        // Valley at 4 and peak at 5 leave no room for het k-mers
+        let hist = BTreeMap::from([(1, 100), (2, 50), (3, 20), (4, 10), (5, 30)]);
+        let (peak, genome_size, het_rate) = KmerCounter::new(21).estimate(&hist).unwrap();
+        assert_eq!(peak, 5);
+        assert_eq!(genome_size, (4.0 * 10.0 + 5.0 * 30.0) / 5.0);
+        assert_eq!(het_rate, 0.0);
     }
-    println!("{:#}", stats);
 }
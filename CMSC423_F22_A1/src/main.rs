@@ -1,135 +1,371 @@
 use std::env;
 use std::fs::{File, OpenOptions};
 use std::fs;
-use std::io::{prelude::*, BufReader, Write};
+use std::io::{prelude::*, BufReader, BufWriter, Write};
 use json::JsonValue;
 use std::process::exit;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Geometric, LogNormal, Normal, WeightedIndex};
+use std::collections::BTreeMap;
 use std::path::Path;
 
+const DNA: [u8; 4] = [b'A', b'C', b'G', b'T'];
+// Phred range written to FASTQ, Illumina 1.8+ style
+const MIN_QUAL: f64 = 2.0;
+const MAX_QUAL: f64 = 41.0;
+// Highest quality given to a base that carries a simulated error
+const MAX_ERROR_QUAL: f64 = 15.0;
+// Error rates used by --long-reads unless given explicitly (nanopore-like,
+// dominated by indels) and its read length sd as a fraction of the mean
+const LONG_READ_ERRORS: [f64; 3] = [0.02, 0.03, 0.04];
+const LONG_READ_LEN_CV: f64 = 0.5;
+// Longest small indel, and the length range of structural variants
+const MAX_INDEL_LEN: u32 = 10;
+const MIN_SV_LEN: u32 = 50;
+const MAX_SV_LEN: u32 = 1000;
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    read_len: u32,
+    target_depth: u32,
+    theta: f32,
+    genome: String,
+    output_stem: String,
+    errors: ErrorModel,
+    // Paired-end mode with a normally distributed insert (fragment) size
+    paired: bool,
+    insert_mean: f64,
+    insert_sd: f64,
+    // Probability that a read (or fragment) comes from the reverse strand
+    strand_prob: f64,
+    // Seed for the simulation RNG; drawn at random when not given
+    seed: Option<u64>,
+    // FASTQ output; qualities follow qual_profile (mean Phred per read
+    // position) when given, otherwise the error model's per-position rate
+    fastq: bool,
+    qual_profile: Option<String>,
+    qual_sd: f64,
+    // Read length distribution: fixed, normal, lognormal or empirical.
+    // read_len is the mean; empirical lengths come from len_hist
+    len_dist: String,
+    len_sd: f64,
+    len_hist: Option<String>,
+    long_reads: bool,
+    // Variants planted in the donor genome reads are sampled from
+    variants: VariantModel,
+    // Write the depth track as bedGraph and the islands as BED
+    depth_track: bool,
+}
+
+// Distribution read lengths are drawn from
+#[derive(Debug, Clone)]
+pub enum LenDist {
+    Fixed(u32),
+    Normal(Normal<f64>),
+    // Log-normal parametrised to have the given mean
+    LogNormal(LogNormal<f64>, f64),
+    // Lengths, how often each occurs, and a sampler over them
+    Empirical(Vec<u32>, Vec<u64>, WeightedIndex<u64>),
+}
+
+// Per-base sequencing error rates. Each rate is multiplied by
+// 1 + ramp * (read position / read length), so a positive ramp makes
+// errors more likely toward the 3' end
+#[derive(Debug, Clone, Default)]
+pub struct ErrorModel {
+    sub_rate: f64,
+    ins_rate: f64,
+    del_rate: f64,
+    ramp: f64,
+}
+
+// Per-base rates of SNPs, small indels (1 to MAX_INDEL_LEN bases) and
+// structural variants (deletions, insertions and inversions of MIN_SV_LEN
+// to MAX_SV_LEN bases) applied to the reference to make the donor genome
+#[derive(Debug, Clone, Default)]
+pub struct VariantModel {
+    snp_rate: f64,
+    indel_rate: f64,
+    sv_rate: f64,
+}
+
+// One planted variant as a VCF record: record index, 1-based position,
+// alleles and INFO column
+#[derive(Debug, Clone)]
+pub struct Variant {
+    contig: usize,
+    pos: u32,
+    ref_allele: String,
+    alt_allele: String,
+    info: String,
+}
+
+// Mean Phred quality at each read position; the last value carries on
+// past the end of the profile
+#[derive(Debug, Clone, Default)]
+pub struct QualModel {
+    profile: Vec<f64>,
+    sd: f64,
+}
+
+// Genome packed two bits per base (A, C, G, T = 0..3), four bases a byte.
+// Anything else (N and other IUPAC codes) is kept as sorted runs and read
+// back as N; soft-masking is not kept
+#[derive(Debug, Clone, Default)]
+pub struct PackedGenome {
+    bits: Vec<u8>,
+    // Half-open runs [start, end) of non-ACGT bases
+    n_runs: Vec<(u32, u32)>,
+    len: u32,
+}
+
+// One genome record, stored at gen[offset..offset + len]
+#[derive(Debug, Clone)]
+pub struct Contig {
+    name: String,
+    offset: u32,
+    len: u32,
+}
+
+// Half-open interval [start, end) of gen on record contig
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    contig: u32,
+    start: u32,
+    end: u32,
+}
+
+// Depth and island accumulators fed each read as it is sampled. Depth
+// takes 2 bytes a base; islands are merged as reads arrive and take memory
+// per island, not per base or per read
+#[derive(Debug, Clone)]
+pub struct ReadCoverage {
+    // Reads starting less reads ending at each base, wrapping. Exact while
+    // fewer than 2^15 reads start or end at any one base
+    diff: Vec<u16>,
+    // Reads overlapping by at least min_overlap bases share an island
+    min_overlap: u32,
+    // Islands so far by the base they start at
+    islands: BTreeMap<u32, Island>,
+}
+
+// An island kept by ReadCoverage. Leaving out reads lying inside others,
+// its reads overlap the next by at least min_overlap, so a new read only
+// needs the start of the last read and the end of the first to be placed
+#[derive(Debug, Clone, Copy)]
+pub struct Island {
+    contig: u32,
+    end: u32,
+    last_start: u32,
+    first_end: u32,
+}
+
+// Lander-Waterman expectations for num_reads reads of length read_len on
+// a genome of records, where reads must overlap by theta * read_len bases
+// to join an island. Islands never span records, so the expectations are
+// summed over records, each drawing reads in proportion to its length
+#[derive(Debug, Clone)]
+pub struct LanderWaterman {
+    coverage: f64,
+    islands: f64,
+    bases_covered: f64,
+    island_len: f64,
+}
+
+// One simulated read: the bases as sequenced, its CIGAR against the
+// forward strand and the genome interval [start, start + span) it covers.
+// Reverse strand reads hold the reverse complement of that interval
+#[derive(Debug, Clone)]
+pub struct SimRead {
+    seq: String,
+    cigar: String,
+    // Edit operations in sequencing order, used to place low qualities
+    ops: Vec<u8>,
+    start: u32,
+    span: u32,
+    strand: char,
+}
+
 fn main() {
     // Reading args
     let args: Vec<_> = env::args().collect();
-    if args.len() != 6 {
-        println!("Invalid parameters");
-        exit(1);
-    }
-    // Reading inputs
-    let read_len: u32 = args[1].parse().unwrap();
-    let target_depth: u32 = args[2].parse().unwrap();
-    let theta: f32 = args[3].parse().unwrap();
-    let genome = &args[4];
-    let output_stem = &args[5];
-    // Creating output files
-    let output_fa = format!("{}.fa", output_stem);
+    let opts = parse_args(&args);
+    let read_len = opts.read_len;
+    let target_depth = opts.target_depth;
+    let theta = opts.theta;
+    let genome = &opts.genome;
+    let output_stem = &opts.output_stem;
+    let errors = &opts.errors;
+    // Creating output files, <stem>_1.fa and <stem>_2.fa for paired reads
+    let ext = if opts.fastq { "fq" } else { "fa" };
+    let output_fas: Vec<String> = if opts.paired {
+        vec![format!("{}_1.{}", output_stem, ext), format!("{}_2.{}", output_stem, ext)]
+    } else {
+        vec![format!("{}.{}", output_stem, ext)]
+    };
     let output_stats = format!("{}.stats", output_stem);
-    if Path::new(&output_fa).exists() {
-        fs::remove_file(&output_fa).unwrap();
+    let mut write_fas: Vec<BufWriter<File>> = Vec::new();
+    for output_fa in &output_fas {
+        if Path::new(output_fa).exists() {
+            fs::remove_file(output_fa).unwrap();
+        }
+        write_fas.push(BufWriter::new(OpenOptions::new().append(true).create(true).open(output_fa).expect("Unable to open file")));
     }
     if Path::new(&output_stats).exists() {
         fs::remove_file(&output_stats).unwrap();
     }
-    let mut write_fa = OpenOptions::new().append(true).create(true).open(output_fa).expect("Unable to open file");   
-    let mut write_stats = OpenOptions::new().append(true).create(true).open(output_stats).expect("Unable to open file");   
-    // Reading genome into gen
+    let mut write_stats = OpenOptions::new().append(true).create(true).open(output_stats).expect("Unable to open file");
+    // Every random draw comes from one seeded generator so runs can be replayed
+    let seed = opts.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    println!("[*] Seed: {}", seed);
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    // Reading genome into gen, records back to back
     let genome_file = File::open(genome).expect("Unable to open file");
     let reader = BufReader::new(genome_file);
-    let mut gen: String = "".to_owned();
-    
-    for line in reader.lines() {
-        if let Ok(curr) = line {
-            match curr.chars().next() {
-                Some(firstchar) => {
-                    if firstchar == '>' {
-                        continue;
-                    } else {
-                        gen.push_str(&curr);
-                    }
-                }
-                None => {
-                    break;
-                }
+    let mut gen = PackedGenome::default();
+    let mut contigs: Vec<Contig> = Vec::new();
+
+    for curr in reader.lines().map_while(Result::ok) {
+        if let Some(header) = curr.strip_prefix('>') {
+            contigs.push(Contig {
+                name: header.split_whitespace().next().unwrap_or("").to_string(),
+                offset: gen.len(),
+                len: 0,
+            });
+        } else {
+            if contigs.is_empty() {
+                println!("Genome must start with a FASTA header");
+                exit(1);
             }
-            
+            gen.push(curr.trim_end().as_bytes());
+            let last = contigs.len() - 1;
+            contigs[last].len = gen.len() - contigs[last].offset;
         }
     }
     println!("[*] Read genome into gen");
-    let gen_len = gen.chars().count() as u32;
-    println!("[*] Size of gen {}", gen_len);
+    // Reads come from the donor genome; positions in read headers are donor
+    // coordinates, the VCF places the variants on the reference
+    let mut variants: Vec<Variant> = Vec::new();
+    if opts.variants.is_active() {
+        let (donor, donor_contigs, planted) = opts.variants.mutate(&gen, &contigs, &mut rng);
+        let output_vcf = format!("{}.vcf", output_stem);
+        if let Err(e) = write_vcf(&output_vcf, genome, &contigs, &planted) {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+        println!("[*] Planted {} variant(s), written to {}", planted.len(), output_vcf);
+        gen = donor;
+        contigs = donor_contigs;
+        variants = planted;
+    }
+    let gen_len = gen.len();
+    println!("[*] Size of gen {} in {} record(s)", gen_len, contigs.len());
+    // Reads are placed on a record with probability proportional to its length.
+    // Records too short to hold a read are never picked
+    let contig_weights: Vec<u32> = contigs.iter().map(|c| if c.len > 1 { c.len } else { 0 }).collect();
+    let contig_index = WeightedIndex::new(&contig_weights).expect("Genome has no usable records");
     // Calculating m (required # of reads to achieve target_depth)
-    let mut gen_array: Vec<i32> = vec![0; gen_len as usize];
-    let mut m: f32 = ((target_depth * gen_len) / read_len) as f32;
-    m = m.ceil();
+    let len_dist = LenDist::new(&opts);
+    let mean_len = len_dist.mean();
+    let m: u64 = (target_depth as f64 * gen_len as f64 / mean_len).ceil() as u64;
 
     println!("[*] Desired m: {}", m);
     println!("[*] Params are:\n  > read_len: {}\n  > target_depth: {}\n  > theta: {}\n  > genome: {}\n  > output_stem: {}\n", read_len, target_depth, theta, genome, output_stem);
-    
+    if errors.is_active() {
+        println!("[*] Error model: {:?}", errors);
+    }
+    println!("[*] Read lengths: {} (mean {:.1})", opts.len_dist, mean_len);
+    let insert_dist = if opts.paired {
+        println!("[*] Paired-end inserts: mean {} sd {}", opts.insert_mean, opts.insert_sd);
+        Some(Normal::new(opts.insert_mean, opts.insert_sd).expect("Invalid insert size distribution"))
+    } else {
+        None
+    };
+    let qual_model = QualModel::new(opts.qual_profile.as_deref(), opts.qual_sd);
+    // Two reads per fragment in paired mode
+    let num_fragments = if opts.paired { m.div_ceil(2) } else { m };
+
     // Generating reads and writing header + read to output_fa
-    // Calculating bases_covered using ranges
-
-    let mut ranges: Vec<Vec<u32>> = Vec::new();
-    for x in 1..=(m as u32) {
-        let num = rand::thread_rng().gen_range(0..(gen_len - read_len));
-        let header = format!(">{}:{}:{}", x-1, num, read_len);
-        let slice = &gen[(num as usize)..((num + read_len) as usize)];
-        for i in num..num+read_len {
-            gen_array[i as usize] += 1;
-        }
-        let range: Vec<u32> = vec![num, num+read_len];
-        let mut to_add: bool = true;
-        // Push ranges if theta overlap
-        for i in 0..ranges.len() {
-            if range[0] <= ranges[i][0] && range[1] >= (ranges[i][0] + (read_len as f32 *theta) as u32) && range[1] <= ranges[i][1] {
-                to_add = false;
-                ranges[i][0] = range[0];
-            } else if range[1] >= ranges[i][1] && range[0] >= ranges[i][0] && range[0] <= (ranges[i][1] - (read_len as f32 *theta) as u32) {
-                to_add = false;
-                ranges[i][1] = range[1];
-            } else if range[0] >= ranges[i][0] && range[1] <= ranges[i][1] {
-                to_add = false;
-            }
-        }
-        if to_add {
-            ranges.push(range);
-        }
-        // Remove unnecessary ranges
-        let mut curr = 0;
-        let runs = ranges.len();
-        for _a in 0..runs {
-            let mut to_remove = false;
-            for i in 0..ranges.len() {
-                if i != curr {
-                    if ranges[curr][0] <= ranges[i][0] && ranges[curr][1] >= (ranges[i][0] + (read_len as f32 *theta) as u32) && ranges[curr][1] <= ranges[i][1]  {
-                        to_remove = true;
-                        ranges[i][0] = ranges[curr][0];
-                    } else if ranges[curr][1] >= ranges[i][1] && ranges[curr][0] >= ranges[i][0] && ranges[curr][0] <= (ranges[i][1] - (read_len as f32 *theta) as u32) {
-                        to_remove = true;
-                        ranges[i][1] = ranges[curr][1];
-                    } else if ranges[curr][0] >= ranges[i][0] && ranges[curr][1] <= ranges[i][1] {
-                        to_remove = true;
-                    }
-                }
+    // Depth (2 bytes a base; the packed genome takes 0.25) and islands.
+    // Reads overlapping by at least read_len * theta bases share an island
+    let mut coverage = ReadCoverage::new(gen_len, (read_len as f32 * theta) as u32);
+    let mut num_reads: u64 = 0;
+    let mut num_bases: u64 = 0;
+    for x in 0..num_fragments {
+        // Header, the read's record and the read
+        let mut records: Vec<(String, usize, SimRead)> = Vec::new();
+        if let Some(dist) = &insert_dist {
+            let contig_id = contig_index.sample(&mut rng);
+            let contig = &contigs[contig_id];
+            let left_len = len_dist.sample(&mut rng, contig.len);
+            let right_len = len_dist.sample(&mut rng, contig.len);
+            // Fragment length clamped so both mates fit inside it and the record
+            let insert = (dist.sample(&mut rng).round() as u32).clamp(left_len.max(right_len), contig.len);
+            let frag_start = rng.gen_range(0..=(contig.len - insert));
+            // Mates are sequenced inward from opposite ends of the fragment;
+            // the first mate reads the strand the fragment came from
+            let left = errors.apply(&gen, contig, frag_start, left_len, '+', &mut rng);
+            let right = errors.apply(&gen, contig, frag_start + insert, right_len, '-', &mut rng);
+            let mates = if rng.gen_bool(opts.strand_prob) { [right, left] } else { [left, right] };
+            for (mate, read) in mates.into_iter().enumerate() {
+                let header = format!(">{}/{}:{}:{}:{} insert={}", x, mate + 1, contig.name, read.start, read.seq.len(), insert);
+                records.push((header, contig_id, read));
             }
-            if to_remove {
-                ranges.remove(curr);
+        } else {
+            let contig_id = contig_index.sample(&mut rng);
+            let contig = &contigs[contig_id];
+            let len = len_dist.sample(&mut rng, contig.len);
+            let num = rng.gen_range(0..(contig.len - len));
+            let read = if rng.gen_bool(opts.strand_prob) {
+                errors.apply(&gen, contig, num + len, len, '-', &mut rng)
             } else {
-                curr += 1;
-            }
-            
+                errors.apply(&gen, contig, num, len, '+', &mut rng)
+            };
+            records.push((format!(">{}:{}:{}:{}", x, contig.name, read.start, read.seq.len()), contig_id, read));
         }
-        //println!("Rand: {}", num);
-        if let Err(e) = writeln!(write_fa, "{}", header) {
-            eprintln!("Couldn't write to file: {}", e);
+
+        for (out, (mut header, contig_id, read)) in write_fas.iter_mut().zip(records) {
+            if opts.paired || opts.strand_prob > 0.0 {
+                header = format!("{} strand={}", header, read.strand);
+            }
+            if errors.is_active() {
+                header = format!("{} cigar={}", header, read.cigar);
+            }
+            coverage.add(contig_id as u32, contigs[contig_id].offset + read.start, read.span);
+            num_reads += 1;
+            num_bases += read.seq.len() as u64;
+            //println!("Rand: {}", num);
+            if opts.fastq {
+                header.replace_range(..1, "@");
+            }
+            if let Err(e) = writeln!(out, "{}", header) {
+                eprintln!("Couldn't write to file: {}", e);
+            }
+            if let Err(e) = writeln!(out, "{}", read.seq) {
+                eprintln!("Couldn't write to file: {}", e);
+            }
+            if opts.fastq {
+                let quals = qual_model.qualities(&read, errors, &mut rng);
+                if let Err(e) = writeln!(out, "+\n{}", quals) {
+                    eprintln!("Couldn't write to file: {}", e);
+                }
+            }
         }
-        if let Err(e) = writeln!(write_fa, "{}", slice) {
+    }
+
+    let islands = coverage.islands();
+    let gen_array = coverage.into_depth();
+    println!("[*] Found {} island(s)", islands.len());
+    if opts.depth_track {
+        let output_bedgraph = format!("{}.bedgraph", output_stem);
+        let output_bed = format!("{}.islands.bed", output_stem);
+        if let Err(e) = write_bedgraph(&output_bedgraph, &contigs, &gen_array)
+            .and_then(|_| write_islands_bed(&output_bed, &contigs, &islands)) {
             eprintln!("Couldn't write to file: {}", e);
         }
-
+        println!("[*] Wrote depth track to {} and islands to {}", output_bedgraph, output_bed);
     }
 
-    
-    println!("[*] Generated ranges: {:?}", ranges);
-
     // Generating stats json output
     let mut stats = json::parse(r#"
         {
@@ -140,33 +376,905 @@ fn main() {
             "num_islands": 0
         }
     "#).unwrap();
-    stats["num_reads"] = JsonValue::from(m as u32);
-    let avg_depth: f32 = m as f32 * read_len as f32 / gen_len as f32;
+    stats["num_reads"] = JsonValue::from(num_reads);
+    let avg_depth: f32 = num_bases as f32 / gen_len as f32;
     stats["avg_depth"] = JsonValue::from(avg_depth);
     println!("[*] Calculated avg_depth: {:.5}", stats["avg_depth"]);
 
     // Calculate bases_covered
     let mut covered_count = 0;
-    for (_pos, e) in gen_array.iter().enumerate() {
+    for e in gen_array.iter() {
         if *e != 0 {
             covered_count += 1;
         }
     }
     println!("[*] Calculated coverage: {}", covered_count as f32 /gen_len as f32);
     // Calculate var_depth
-    let mut var: f32 = 0.0;
-    for (_pos, e) in gen_array.iter().enumerate() {
-        var += (*e as f32 - avg_depth as f32).powf(2.0);
+    let mut var: f64 = 0.0;
+    for e in gen_array.iter() {
+        var += (*e as f64 - avg_depth as f64).powf(2.0);
     }
     println!("[*] Calculated var: {}", var);
-    let var_depth: f32 = var as f32 / (gen_len as f32 - 1.0) as f32;
+    let var_depth: f32 = (var / (gen_len as f64 - 1.0)) as f32;
     println!("[*] Calculated Var_Depth: {:.5}", var_depth);
 
     stats["bases_covered"] = JsonValue::from(covered_count);
     stats["var_depth"] = JsonValue::from(var_depth);
-    stats["num_islands"] = JsonValue::from(ranges.len());
+    stats["num_islands"] = JsonValue::from(islands.len());
+    // Observed islands and coverage against Lander-Waterman expectations for
+    // the reads actually drawn. Deviations are (observed - expected) / expected
+    let lw = LanderWaterman::new(num_reads, num_bases as f64 / num_reads as f64, &contigs, theta as f64);
+    let observed_island_len = islands.iter().map(|i| (i.end - i.start) as f64).sum::<f64>() / islands.len() as f64;
+    let deviation = |observed: f64, expected: f64| (observed - expected) / expected;
+    stats["lander_waterman"] = json::object! {
+        "coverage": lw.coverage,
+        "expected_islands": lw.islands,
+        "islands_deviation": deviation(islands.len() as f64, lw.islands),
+        "expected_bases_covered": lw.bases_covered,
+        "bases_covered_deviation": deviation(covered_count as f64, lw.bases_covered),
+        "expected_island_len": lw.island_len,
+        "observed_island_len": observed_island_len,
+        "island_len_deviation": deviation(observed_island_len, lw.island_len),
+    };
+    println!("[*] Lander-Waterman: {:.1} island(s) expected, {} observed; {:.0} bases covered expected, {} observed",
+        lw.islands, islands.len(), lw.bases_covered, covered_count);
+    // Kept as a string since JSON numbers lose precision past 2^53
+    stats["seed"] = JsonValue::from(seed.to_string());
+    if errors.is_active() {
+        stats["sub_rate"] = JsonValue::from(errors.sub_rate);
+        stats["ins_rate"] = JsonValue::from(errors.ins_rate);
+        stats["del_rate"] = JsonValue::from(errors.del_rate);
+        stats["error_ramp"] = JsonValue::from(errors.ramp);
+    }
+    if opts.len_dist != "fixed" {
+        stats["len_dist"] = JsonValue::from(opts.len_dist.as_str());
+        stats["mean_read_len"] = JsonValue::from(num_bases as f64 / num_reads as f64);
+    }
+    if opts.strand_prob > 0.0 {
+        stats["strand_prob"] = JsonValue::from(opts.strand_prob);
+    }
+    if opts.variants.is_active() {
+        let count = |kind: &str| variants.iter().filter(|v| v.kind() == kind).count();
+        stats["num_snps"] = JsonValue::from(count("SNP"));
+        stats["num_indels"] = JsonValue::from(count("INDEL"));
+        stats["num_svs"] = JsonValue::from(count("SV"));
+    }
+    if opts.paired {
+        stats["num_pairs"] = JsonValue::from(num_fragments);
+        stats["insert_mean"] = JsonValue::from(opts.insert_mean);
+        stats["insert_sd"] = JsonValue::from(opts.insert_sd);
+    }
 
     if let Err(e) = writeln!(write_stats, "{:#}", stats) {
         eprintln!("Couldn't write to file: {}", e);
     }
 }
+
+// Positional args are read_len target_depth theta genome output_stem;
+// options may appear anywhere
+pub fn parse_args(args: &[String]) -> Options {
+    let mut opts = Options {
+        qual_sd: 2.0,
+        len_dist: "fixed".to_string(),
+        ..Default::default()
+    };
+    let mut positional: Vec<&String> = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--paired" => opts.paired = true,
+            "--fastq" => opts.fastq = true,
+            "--long-reads" => opts.long_reads = true,
+            "--depth-track" => opts.depth_track = true,
+            "--len-dist" if i + 1 < args.len() => {
+                opts.len_dist = args[i + 1].clone();
+                i += 1;
+            }
+            "--len-hist" if i + 1 < args.len() => {
+                opts.len_hist = Some(args[i + 1].clone());
+                opts.len_dist = "empirical".to_string();
+                i += 1;
+            }
+            "--qual-profile" if i + 1 < args.len() => {
+                opts.qual_profile = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--seed" if i + 1 < args.len() => {
+                opts.seed = Some(parse_num(&args[i + 1]));
+                i += 1;
+            }
+            name if name.starts_with("--") && i + 1 < args.len() => {
+                let value: f64 = parse_num(&args[i + 1]);
+                if value < 0.0 {
+                    invalid_parameters();
+                }
+                match name {
+                    "--sub-rate" => opts.errors.sub_rate = value,
+                    "--ins-rate" => opts.errors.ins_rate = value,
+                    "--del-rate" => opts.errors.del_rate = value,
+                    "--error-ramp" => opts.errors.ramp = value,
+                    "--insert-mean" => opts.insert_mean = value,
+                    "--insert-sd" => opts.insert_sd = value,
+                    "--strand-prob" if value <= 1.0 => opts.strand_prob = value,
+                    "--qual-sd" => opts.qual_sd = value,
+                    "--len-sd" => opts.len_sd = value,
+                    "--snp-rate" if value <= 1.0 => opts.variants.snp_rate = value,
+                    "--indel-rate" if value <= 1.0 => opts.variants.indel_rate = value,
+                    "--sv-rate" if value <= 1.0 => opts.variants.sv_rate = value,
+                    _ => invalid_parameters(),
+                }
+                i += 1;
+            }
+            _ => positional.push(&args[i]),
+        }
+        i += 1;
+    }
+    if positional.len() != 5 {
+        invalid_parameters();
+    }
+    // Reading inputs
+    opts.read_len = parse_num(positional[0]);
+    opts.target_depth = parse_num(positional[1]);
+    opts.theta = parse_num(positional[2]);
+    opts.genome = positional[3].clone();
+    opts.output_stem = positional[4].clone();
+    if opts.long_reads {
+        // Presets only fill in what was not given explicitly
+        let rates = [&mut opts.errors.sub_rate, &mut opts.errors.ins_rate, &mut opts.errors.del_rate];
+        for (rate, preset) in rates.into_iter().zip(LONG_READ_ERRORS) {
+            if *rate == 0.0 {
+                *rate = preset;
+            }
+        }
+        if opts.len_dist == "fixed" {
+            opts.len_dist = "lognormal".to_string();
+        }
+        if opts.len_sd == 0.0 {
+            opts.len_sd = opts.read_len as f64 * LONG_READ_LEN_CV;
+        }
+    }
+    if !["fixed", "normal", "lognormal", "empirical"].contains(&opts.len_dist.as_str())
+        || (opts.len_dist == "empirical" && opts.len_hist.is_none()) {
+        invalid_parameters();
+    }
+    if opts.paired && opts.insert_mean < opts.read_len as f64 {
+        println!("--insert-mean must be at least read_len");
+        exit(1);
+    }
+    opts
+}
+
+pub fn parse_num<T: std::str::FromStr>(arg: &str) -> T {
+    match arg.parse() {
+        Ok(num) => num,
+        Err(_) => invalid_parameters(),
+    }
+}
+
+pub fn invalid_parameters() -> ! {
+    println!("Invalid parameters");
+    exit(1);
+}
+
+impl LanderWaterman {
+    pub fn new(num_reads: u64, read_len: f64, contigs: &[Contig], theta: f64) -> LanderWaterman {
+        let sigma = 1.0 - theta;
+        // Records too short to hold a read get none, as when sampling
+        let usable: f64 = contigs.iter().filter(|c| c.len > 1).map(|c| c.len as f64).sum();
+        let gen_len: f64 = contigs.iter().map(|c| c.len as f64).sum();
+        let mut lw = LanderWaterman {
+            coverage: num_reads as f64 * read_len / gen_len,
+            islands: 0.0,
+            bases_covered: 0.0,
+            island_len: 0.0,
+        };
+        for contig in contigs.iter().filter(|c| c.len > 1) {
+            let len = contig.len as f64;
+            let n = num_reads as f64 * len / usable;
+            let c = n * read_len / len;
+            // Each read but a record's last starts a gap with probability
+            // e^(-c sigma); the last always ends an island. With Poisson
+            // read counts E[(n - 1)+] = n - 1 + e^(-n)
+            let islands = (n - 1.0 + (-n).exp()) * (-c * sigma).exp() + 1.0 - (-n).exp();
+            let island_len = (read_len * (((c * sigma).exp() - 1.0) / c + 1.0 - sigma)).min(len);
+            lw.islands += islands;
+            lw.bases_covered += len * (1.0 - (-c).exp());
+            lw.island_len += islands * island_len;
+        }
+        lw.island_len /= lw.islands;
+        lw
+    }
+}
+
+impl ReadCoverage {
+    pub fn new(gen_len: u32, min_overlap: u32) -> ReadCoverage {
+        ReadCoverage {
+            diff: vec![0; gen_len as usize + 1],
+            min_overlap,
+            islands: BTreeMap::new(),
+        }
+    }
+
+    // Records a read covering [start, start + span) of gen on record contig.
+    // Islands are those of a sweep over the reads in order of start: a read
+    // joins the island before it if it lies inside it or overlaps it by at
+    // least min_overlap bases, and islands never span two records. A new
+    // read can only join islands, never split one, so they are kept merged
+    pub fn add(&mut self, contig: u32, start: u32, span: u32) {
+        if span == 0 {
+            return;
+        }
+        let (pos, end) = (start as usize, start + span);
+        self.diff[pos] = self.diff[pos].wrapping_add(1);
+        self.diff[pos + span as usize] = self.diff[pos + span as usize].wrapping_sub(1);
+        let joined = match self.islands.range_mut(..=start).next_back() {
+            Some((island_start, island)) if island.contig == contig => {
+                if end <= island.end {
+                    // Inside the island, only ever replacing its first read
+                    if *island_start == start {
+                        island.first_end = island.first_end.max(end);
+                    }
+                    return;
+                }
+                // Reads before the last one overlap the next read after
+                // them, and so this one, by at least min_overlap
+                if start <= island.last_start || island.end >= start + self.min_overlap {
+                    island.end = end;
+                    island.last_start = start;
+                    if *island_start == start {
+                        island.first_end = end;
+                    }
+                    Some(*island_start)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let key = joined.unwrap_or_else(|| {
+            self.islands.insert(start, Island { contig, end, last_start: start, first_end: end });
+            start
+        });
+        // The read now ends its island and may reach the islands after it:
+        // it joins one it overlaps by min_overlap or whose first read it
+        // covers (the rest then overlap it), and keeps going past any it
+        // covers entirely
+        while let Some((&next_start, &next)) = self.islands.range(key + 1..).next() {
+            if next.contig != contig || (end < next_start + self.min_overlap && end < next.first_end) {
+                break;
+            }
+            self.islands.remove(&next_start);
+            let island = self.islands.get_mut(&key).unwrap();
+            if next.end > end {
+                island.end = next.end;
+                island.last_start = next.last_start;
+                break;
+            }
+        }
+    }
+
+    // Islands in order of start
+    pub fn islands(&self) -> Vec<Interval> {
+        self.islands.iter().map(|(start, island)| Interval { contig: island.contig, start: *start, end: island.end }).collect()
+    }
+
+    // Read depth at every base of gen, saturating at u16::MAX, summed in
+    // place over the start/end differences
+    pub fn into_depth(self) -> Vec<u16> {
+        let mut depth = self.diff;
+        let mut running: i64 = 0;
+        for d in depth.iter_mut() {
+            running += *d as i16 as i64;
+            *d = running.clamp(0, u16::MAX as i64) as u16;
+        }
+        depth.pop();
+        depth
+    }
+}
+
+// Writes depth as bedGraph, one line per run of equal depth within a
+// record, in 0-based half-open record coordinates
+pub fn write_bedgraph(path: &str, contigs: &[Contig], depth: &[u16]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "track type=bedGraph name=randsim_depth description=\"Simulated read depth\"")?;
+    for contig in contigs {
+        let bases = &depth[contig.offset as usize..(contig.offset + contig.len) as usize];
+        let mut start = 0;
+        while start < bases.len() {
+            let mut end = start;
+            while end < bases.len() && bases[end] == bases[start] {
+                end += 1;
+            }
+            writeln!(out, "{}\t{}\t{}\t{}", contig.name, start, end, bases[start])?;
+            start = end;
+        }
+    }
+    out.flush()
+}
+
+// Writes islands as BED in record coordinates, named island_1, island_2, ...
+pub fn write_islands_bed(path: &str, contigs: &[Contig], islands: &[Interval]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for (i, island) in islands.iter().enumerate() {
+        let contig = &contigs[island.contig as usize];
+        writeln!(out, "{}\t{}\t{}\tisland_{}", contig.name, island.start - contig.offset,
+            island.end - contig.offset, i + 1)?;
+    }
+    out.flush()
+}
+
+impl ErrorModel {
+    pub fn is_active(&self) -> bool {
+        self.sub_rate > 0.0 || self.ins_rate > 0.0 || self.del_rate > 0.0
+    }
+
+    // Rate multiplier at read position pos
+    pub fn scale(&self, pos: usize, read_len: usize) -> f64 {
+        1.0 + self.ramp * pos as f64 / read_len as f64
+    }
+
+    // Probability that the base sequenced at read position pos is wrong
+    pub fn base_error(&self, pos: usize, read_len: usize) -> f64 {
+        ((self.sub_rate + self.ins_rate) * self.scale(pos, read_len)).min(1.0)
+    }
+
+    // Sequences read_len bases of contig, introducing errors. Forward reads start
+    // at anchor; reverse reads end just before anchor and are read backwards
+    // on the complementary strand. The CIGAR uses extended operations
+    // (=, X, I, D) against the forward strand of gen
+    pub fn apply(&self, gen: &PackedGenome, contig: &Contig, anchor: u32, read_len: u32, strand: char, rng: &mut impl Rng) -> SimRead {
+        let anchor = anchor as usize;
+        let read_len = read_len as usize;
+        // Record bases available in sequencing order
+        let avail = if strand == '+' { contig.len as usize - anchor } else { anchor };
+        let base = |i: usize| gen.base(contig.offset + i as u32);
+        let base_at = |t: usize| if strand == '+' { base(anchor + t) } else { complement(base(anchor - 1 - t)) };
+        let mut read: Vec<u8> = Vec::with_capacity(read_len);
+        let mut ops: Vec<u8> = Vec::with_capacity(read_len);
+        let mut t = 0;
+        if !self.is_active() {
+            t = read_len.min(avail);
+            read.extend((0..t).map(base_at));
+            ops.resize(t, b'=');
+        }
+        while read.len() < read_len && t < avail {
+            let scale = self.scale(read.len(), read_len);
+            let r: f64 = rng.gen();
+            // Deletions are kept off the read ends so the CIGAR starts and ends
+            // on a read base; a deletion drawn there is read as a match
+            let deletion = r < self.del_rate * scale;
+            if deletion && !read.is_empty() && t + 1 < avail {
+                ops.push(b'D');
+                t += 1;
+            } else if !deletion && r < (self.del_rate + self.ins_rate) * scale {
+                ops.push(b'I');
+                read.push(DNA[rng.gen_range(0..4)]);
+            } else if !deletion && r < (self.del_rate + self.ins_rate + self.sub_rate) * scale {
+                // Substitute with one of the three other bases
+                let orig = DNA.iter().position(|b| *b == base_at(t).to_ascii_uppercase()).unwrap_or(0);
+                ops.push(b'X');
+                read.push(DNA[(orig + rng.gen_range(1..4)) % 4]);
+                t += 1;
+            } else {
+                ops.push(b'=');
+                read.push(base_at(t));
+                t += 1;
+            }
+        }
+        let mut fwd_ops = ops.clone();
+        if strand == '-' {
+            fwd_ops.reverse();
+        }
+        SimRead {
+            seq: String::from_utf8(read).unwrap(),
+            cigar: compress_cigar(&fwd_ops),
+            ops,
+            start: if strand == '+' { anchor as u32 } else { (anchor - t) as u32 },
+            span: t as u32,
+            strand,
+        }
+    }
+}
+
+impl LenDist {
+    pub fn new(opts: &Options) -> LenDist {
+        let mean = opts.read_len as f64;
+        let sd = opts.len_sd;
+        match opts.len_dist.as_str() {
+            "normal" => LenDist::Normal(Normal::new(mean, sd).expect("Invalid read length distribution")),
+            "lognormal" => {
+                // Parameters of the underlying normal giving this mean and sd
+                let sigma2 = (1.0 + sd * sd / (mean * mean)).ln();
+                let mu = mean.ln() - sigma2 / 2.0;
+                LenDist::LogNormal(LogNormal::new(mu, sigma2.sqrt()).expect("Invalid read length distribution"), mean)
+            }
+            "empirical" => {
+                // Histogram file of "length count" lines
+                let path = opts.len_hist.as_ref().unwrap();
+                let contents = fs::read_to_string(path).expect("Unable to open file");
+                let mut lengths: Vec<u32> = Vec::new();
+                let mut counts: Vec<u64> = Vec::new();
+                for line in contents.lines() {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    if fields.len() == 2 {
+                        lengths.push(parse_num(fields[0]));
+                        counts.push(parse_num(fields[1]));
+                    }
+                }
+                match WeightedIndex::new(&counts) {
+                    Ok(index) => LenDist::Empirical(lengths, counts, index),
+                    Err(_) => {
+                        println!("Length histogram {} has no usable entries", path);
+                        exit(1);
+                    }
+                }
+            }
+            _ => LenDist::Fixed(opts.read_len),
+        }
+    }
+
+    // Draws a length, kept between 1 and one less than the record length
+    pub fn sample(&self, rng: &mut impl Rng, contig_len: u32) -> u32 {
+        let len = match self {
+            LenDist::Fixed(len) => *len,
+            LenDist::Normal(dist) => dist.sample(rng).round() as u32,
+            LenDist::LogNormal(dist, _) => dist.sample(rng).round() as u32,
+            LenDist::Empirical(lengths, _, index) => lengths[index.sample(rng)],
+        };
+        len.clamp(1, contig_len - 1)
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self {
+            LenDist::Fixed(len) => *len as f64,
+            LenDist::Normal(dist) => dist.mean(),
+            LenDist::LogNormal(_, mean) => *mean,
+            LenDist::Empirical(lengths, counts, _) => {
+                let total: u64 = counts.iter().sum();
+                let bases: u64 = lengths.iter().zip(counts.iter()).map(|(len, count)| *len as u64 * count).sum();
+                bases as f64 / total as f64
+            }
+        }
+    }
+}
+
+impl VariantModel {
+    pub fn is_active(&self) -> bool {
+        self.snp_rate > 0.0 || self.indel_rate > 0.0 || self.sv_rate > 0.0
+    }
+
+    // Builds the donor genome from the reference, returning it with its
+    // records and the variants planted in them. Variants never overlap and
+    // leave an unchanged base before them to anchor indels in the VCF
+    pub fn mutate(&self, gen: &PackedGenome, contigs: &[Contig], rng: &mut impl Rng) -> (PackedGenome, Vec<Contig>, Vec<Variant>) {
+        let rate = (self.snp_rate + self.indel_rate + self.sv_rate).min(1.0);
+        let gap = Geometric::new(rate).expect("Invalid variant rates");
+        let mut donor = PackedGenome::default();
+        let mut donor_contigs: Vec<Contig> = Vec::new();
+        let mut variants: Vec<Variant> = Vec::new();
+        for (index, contig) in contigs.iter().enumerate() {
+            // One record unpacked at a time
+            let seq = &gen.slice(contig.offset, contig.offset + contig.len);
+            let offset = donor.len();
+            let mut donor_seq: Vec<u8> = Vec::with_capacity(seq.len());
+            // Reference bases before cursor are already in donor_seq
+            let mut cursor = 0;
+            let mut pos = 1;
+            loop {
+                pos += gap.sample(rng) as usize;
+                if pos >= seq.len() {
+                    break;
+                }
+                donor_seq.extend_from_slice(&seq[cursor..pos]);
+                let draw = rng.gen::<f64>() * rate;
+                let (consumed, variant) = if draw < self.snp_rate {
+                    plant_snp(seq, pos, rng)
+                } else if draw < self.snp_rate + self.indel_rate {
+                    let len = rng.gen_range(1..=MAX_INDEL_LEN) as usize;
+                    let kind = if rng.gen_bool(0.5) { "INS" } else { "DEL" };
+                    plant_indel(seq, pos, len, kind, None, rng)
+                } else {
+                    let len = rng.gen_range(MIN_SV_LEN..=MAX_SV_LEN) as usize;
+                    let kind = ["DEL", "INS", "INV"][rng.gen_range(0..3)];
+                    plant_indel(seq, pos, len, kind, Some(kind), rng)
+                };
+                match variant {
+                    Some(mut variant) => {
+                        variant.contig = index;
+                        donor_seq.extend_from_slice(variant.alt_bases(seq, pos).as_bytes());
+                        variants.push(variant);
+                    }
+                    None => donor_seq.extend_from_slice(&seq[pos..pos + consumed]),
+                }
+                cursor = pos + consumed;
+                pos = cursor + 1;
+            }
+            donor_seq.extend_from_slice(&seq[cursor.min(seq.len())..]);
+            donor.push(&donor_seq);
+            donor_contigs.push(Contig {
+                name: contig.name.clone(),
+                offset,
+                len: donor_seq.len() as u32,
+            });
+        }
+        (donor, donor_contigs, variants)
+    }
+}
+
+// Substitutes the base at pos (0-based) with a different one. Ambiguous
+// bases are left alone
+fn plant_snp(seq: &[u8], pos: usize, rng: &mut impl Rng) -> (usize, Option<Variant>) {
+    let base = seq[pos].to_ascii_uppercase();
+    if !DNA.contains(&base) {
+        return (1, None);
+    }
+    let alts: Vec<u8> = DNA.iter().copied().filter(|b| *b != base).collect();
+    let alt = alts[rng.gen_range(0..alts.len())];
+    (1, Some(Variant {
+        contig: 0,
+        pos: pos as u32 + 1,
+        ref_allele: (seq[pos] as char).to_string(),
+        alt_allele: (alt as char).to_string(),
+        info: String::new(),
+    }))
+}
+
+// Plants a len base insertion, deletion or inversion right after the
+// anchor base at pos (0-based). Structural variants carry SVTYPE/SVLEN/END
+// and inversions use the symbolic <INV> allele. Returns the number of
+// reference bases consumed, starting at pos
+fn plant_indel(seq: &[u8], pos: usize, len: usize, kind: &str, sv_type: Option<&str>, rng: &mut impl Rng) -> (usize, Option<Variant>) {
+    let anchor = seq[pos] as char;
+    let (consumed, ref_allele, alt_allele, sv_len) = match kind {
+        "INS" => {
+            let inserted: String = (0..len).map(|_| DNA[rng.gen_range(0..4)] as char).collect();
+            (1, anchor.to_string(), format!("{}{}", anchor, inserted), len as i64)
+        }
+        _ if pos + 1 + len > seq.len() => return (1, None),
+        "DEL" => {
+            let deleted = String::from_utf8_lossy(&seq[pos..pos + 1 + len]).to_string();
+            (1 + len, deleted, anchor.to_string(), -(len as i64))
+        }
+        _ => (1 + len, anchor.to_string(), "<INV>".to_string(), len as i64),
+    };
+    let pos = pos as u32 + 1;
+    let info = match sv_type {
+        // END is the last reference base the variant covers
+        Some(sv_type) => format!("SVTYPE={};SVLEN={};END={}", sv_type, sv_len, pos + consumed as u32 - 1),
+        None => String::new(),
+    };
+    (consumed, Some(Variant { contig: 0, pos, ref_allele, alt_allele, info }))
+}
+
+impl Variant {
+    // SNP, INDEL or SV, as counted in the stats
+    pub fn kind(&self) -> &str {
+        if !self.info.is_empty() {
+            "SV"
+        } else if self.ref_allele.len() == 1 && self.alt_allele.len() == 1 {
+            "SNP"
+        } else {
+            "INDEL"
+        }
+    }
+
+    // Donor bases replacing the reference bases the variant covers, where
+    // pos is the 0-based position of its first reference base
+    pub fn alt_bases(&self, seq: &[u8], pos: usize) -> String {
+        if self.alt_allele != "<INV>" {
+            return self.alt_allele.clone();
+        }
+        let end = pos + self.ref_span();
+        let inverted: Vec<u8> = seq[pos + 1..end].iter().rev().map(|b| complement(*b)).collect();
+        format!("{}{}", seq[pos] as char, String::from_utf8_lossy(&inverted))
+    }
+
+    // Number of reference bases from pos through END
+    pub fn ref_span(&self) -> usize {
+        if self.alt_allele == "<INV>" {
+            let end: usize = self.info.rsplit("END=").next().map_or(0, parse_num);
+            end + 1 - self.pos as usize
+        } else {
+            self.ref_allele.len()
+        }
+    }
+}
+
+// Writes variants as VCF 4.2 against the reference records
+pub fn write_vcf(path: &str, reference: &str, contigs: &[Contig], variants: &[Variant]) -> std::io::Result<()> {
+    let mut out = File::create(path)?;
+    writeln!(out, "##fileformat=VCFv4.2")?;
+    writeln!(out, "##source=randsim")?;
+    writeln!(out, "##reference={}", reference)?;
+    for contig in contigs {
+        writeln!(out, "##contig=<ID={},length={}>", contig.name, contig.len)?;
+    }
+    writeln!(out, "##ALT=<ID=INV,Description=\"Inversion\">")?;
+    writeln!(out, "##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">")?;
+    writeln!(out, "##INFO=<ID=SVLEN,Number=1,Type=Integer,Description=\"Difference in length between ALT and REF\">")?;
+    writeln!(out, "##INFO=<ID=END,Number=1,Type=Integer,Description=\"End position of the variant\">")?;
+    writeln!(out, "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO")?;
+    for variant in variants {
+        let info = if variant.info.is_empty() { "." } else { &variant.info };
+        writeln!(out, "{}\t{}\t.\t{}\t{}\t.\tPASS\t{}", contigs[variant.contig].name, variant.pos,
+            variant.ref_allele, variant.alt_allele, info)?;
+    }
+    Ok(())
+}
+
+impl PackedGenome {
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Appends bases, which may be upper or lower case
+    pub fn push(&mut self, bases: &[u8]) {
+        for base in bases {
+            if (self.len as u64) >= u32::MAX as u64 {
+                println!("Genome longer than {} bases is not supported", u32::MAX);
+                exit(1);
+            }
+            let code = match base.to_ascii_uppercase() {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => {
+                    match self.n_runs.last_mut() {
+                        Some(run) if run.1 == self.len => run.1 += 1,
+                        _ => self.n_runs.push((self.len, self.len + 1)),
+                    }
+                    0
+                }
+            };
+            if self.len.is_multiple_of(4) {
+                self.bits.push(0);
+            }
+            let last = self.bits.len() - 1;
+            self.bits[last] |= code << (2 * (self.len % 4));
+            self.len += 1;
+        }
+    }
+
+    pub fn base(&self, pos: u32) -> u8 {
+        let run = self.n_runs.partition_point(|run| run.1 <= pos);
+        if run < self.n_runs.len() && self.n_runs[run].0 <= pos {
+            return b'N';
+        }
+        DNA[((self.bits[pos as usize / 4] >> (2 * (pos % 4))) & 3) as usize]
+    }
+
+    // Bases [start, end)
+    pub fn slice(&self, start: u32, end: u32) -> Vec<u8> {
+        (start..end).map(|pos| self.base(pos)).collect()
+    }
+}
+
+impl QualModel {
+    pub fn new(profile_path: Option<&str>, sd: f64) -> QualModel {
+        let mut profile: Vec<f64> = Vec::new();
+        if let Some(path) = profile_path {
+            let contents = fs::read_to_string(path).expect("Unable to open file");
+            profile = contents.split_whitespace().map(parse_num).collect();
+            if profile.is_empty() {
+                println!("Quality profile {} is empty", path);
+                exit(1);
+            }
+        }
+        QualModel { profile, sd }
+    }
+
+    // Phred+33 quality string for read. Correct bases are drawn around the
+    // position's mean quality, bases carrying an error get a low quality
+    pub fn qualities(&self, read: &SimRead, errors: &ErrorModel, rng: &mut impl Rng) -> String {
+        let read_len = read.seq.len();
+        let mut quals = String::with_capacity(read.seq.len());
+        let bases = read.ops.iter().filter(|op| **op != b'D');
+        for (pos, op) in bases.enumerate() {
+            let mean = match self.profile.get(pos).or(self.profile.last()) {
+                Some(q) => *q,
+                None => phred(errors.base_error(pos, read_len)),
+            };
+            let q = if *op == b'X' || *op == b'I' {
+                rng.gen_range(MIN_QUAL..=mean.clamp(MIN_QUAL, MAX_ERROR_QUAL))
+            } else {
+                Normal::new(mean, self.sd).map_or(mean, |dist| dist.sample(rng))
+            };
+            quals.push((q.round().clamp(MIN_QUAL, MAX_QUAL) as u8 + 33) as char);
+        }
+        quals
+    }
+}
+
+// Phred score for an error probability, capped at MAX_QUAL
+pub fn phred(p: f64) -> f64 {
+    if p <= 0.0 {
+        return MAX_QUAL;
+    }
+    (-10.0 * p.log10()).min(MAX_QUAL)
+}
+
+// Run-length encodes a string of CIGAR operations, e.g. "===X=" -> "3=1X1="
+pub fn compress_cigar(ops: &[u8]) -> String {
+    let mut cigar = String::new();
+    let mut i = 0;
+    while i < ops.len() {
+        let mut j = i;
+        while j < ops.len() && ops[j] == ops[i] {
+            j += 1;
+        }
+        cigar.push_str(&format!("{}{}", j - i, ops[i] as char));
+        i = j;
+    }
+    cigar
+}
+
+pub fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(contig: u32, start: u32, end: u32) -> Interval {
+        Interval { contig, start, end }
+    }
+
+    fn contig(offset: u32, len: u32) -> Contig {
+        Contig { name: String::new(), offset, len }
+    }
+
+    fn coverage(gen_len: u32, min_overlap: u32, intervals: &[Interval]) -> ReadCoverage {
+        let mut coverage = ReadCoverage::new(gen_len, min_overlap);
+        for i in intervals {
+            coverage.add(i.contig, i.start, i.end - i.start);
+        }
+        coverage
+    }
+
+    // Merges any two islands that touch by min_overlap or nest until
+    // nothing changes
+    fn brute_force_islands(intervals: &[Interval], min_overlap: u32) -> Vec<Interval> {
+        let mut islands = intervals.to_vec();
+        let mut merged = true;
+        while merged {
+            merged = false;
+            'outer: for i in 0..islands.len() {
+                for j in 0..islands.len() {
+                    let (a, b) = (islands[i], islands[j]);
+                    if i == j || a.contig != b.contig || a.start > b.start {
+                        continue;
+                    }
+                    let nested = b.end <= a.end || (a.start == b.start && a.end <= b.end);
+                    if nested || a.end >= b.start + min_overlap {
+                        islands[i].end = a.end.max(b.end);
+                        islands.remove(j);
+                        merged = true;
+                        break 'outer;
+                    }
+                }
+            }
+        }
+        islands.sort_by_key(|i| i.start);
+        islands
+    }
+
+    #[test]
+    fn islands_join_on_overlap() {
+        let intervals = vec![interval(0, 0, 100), interval(0, 70, 170), interval(0, 160, 260)];
+        assert_eq!(coverage(300, 30, &intervals).islands(), vec![interval(0, 0, 170), interval(0, 160, 260)]);
+        assert_eq!(coverage(300, 10, &intervals).islands(), vec![interval(0, 0, 260)]);
+    }
+
+    #[test]
+    fn contained_intervals_join() {
+        // Shorter than the overlap threshold, which used to underflow
+        let coverage = coverage(100, 30, &[interval(0, 50, 60), interval(0, 0, 100), interval(0, 50, 55)]);
+        assert_eq!(coverage.islands(), vec![interval(0, 0, 100)]);
+    }
+
+    #[test]
+    fn islands_stop_at_record_boundaries() {
+        let coverage = coverage(200, 0, &[interval(0, 0, 100), interval(1, 100, 200)]);
+        assert_eq!(coverage.islands().len(), 2);
+    }
+
+    #[test]
+    fn islands_match_brute_force() {
+        let mut rng = ChaCha8Rng::seed_from_u64(17);
+        let contigs = [contig(0, 600), contig(600, 600)];
+        for _ in 0..200 {
+            let n = rng.gen_range(1..40);
+            let min_overlap = rng.gen_range(0..30);
+            let intervals: Vec<Interval> = (0..n).map(|_| {
+                let id = rng.gen_range(0..2);
+                let start = contigs[id as usize].offset + rng.gen_range(0..500);
+                interval(id, start, start + rng.gen_range(1..60))
+            }).collect();
+            let expected = brute_force_islands(&intervals, min_overlap);
+            assert_eq!(coverage(1200, min_overlap, &intervals).islands(), expected);
+        }
+    }
+
+    #[test]
+    fn depth_matches_per_base_count() {
+        let mut rng = ChaCha8Rng::seed_from_u64(3);
+        let intervals: Vec<Interval> = (0..100).map(|_| {
+            let start = rng.gen_range(0..900);
+            interval(0, start, start + rng.gen_range(1..=100))
+        }).collect();
+        let mut expected = vec![0; 1000];
+        for i in &intervals {
+            for base in i.start..i.end {
+                expected[base as usize] += 1;
+            }
+        }
+        assert_eq!(coverage(1000, 0, &intervals).into_depth(), expected);
+    }
+
+    #[test]
+    fn long_reads_are_kept_exactly() {
+        let intervals = [interval(0, 10, 20), interval(0, 80_000, 80_100), interval(0, 10, 80_010)];
+        let coverage = coverage(90_000, 30, &intervals);
+        assert_eq!(coverage.islands(), vec![interval(0, 10, 80_010), interval(0, 80_000, 80_100)]);
+        let depth = coverage.into_depth();
+        assert_eq!((depth[9], depth[10], depth[20], depth[80_005], depth[80_050]), (0, 2, 1, 2, 1));
+    }
+
+    #[test]
+    fn deletions_stay_off_read_ends() {
+        let mut gen = PackedGenome::default();
+        gen.push(b"ACGTACGTAC");
+        let contig = Contig { name: "g".to_string(), offset: 0, len: 10 };
+        let errors = ErrorModel { del_rate: 1.0, ..Default::default() };
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        for strand in ['+', '-'] {
+            let anchor = if strand == '+' { 0 } else { 10 };
+            let read = errors.apply(&gen, &contig, anchor, 5, strand, &mut rng);
+            // Every draw is a deletion, so only the record ends are read
+            assert_eq!(read.ops, b"=DDDDDDDD=");
+            assert_eq!(read.seq.len(), 2);
+            assert_eq!(read.span, 10);
+        }
+    }
+
+    #[test]
+    fn lander_waterman_per_record() {
+        let contig = |offset: u32, len: u32| Contig { name: String::new(), offset, len };
+        // One long record matches the textbook N e^(-c sigma)
+        let lw = LanderWaterman::new(1000, 100.0, &[contig(0, 1_000_000)], 0.3);
+        assert!((lw.coverage - 0.1).abs() < 1e-12);
+        assert!((lw.islands - 1000.0 * (-0.07f64).exp()).abs() < 1.0);
+        // Deep coverage of two records still leaves one island in each,
+        // no longer than its record
+        let lw = LanderWaterman::new(1600, 100.0, &[contig(0, 4000), contig(4000, 4000)], 0.3);
+        assert!((lw.islands - 2.0).abs() < 0.01);
+        assert!(lw.island_len <= 4000.0);
+        assert!((lw.bases_covered - 8000.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn packed_genome_round_trips() {
+        let mut gen = PackedGenome::default();
+        gen.push(b"ACGTNNac");
+        gen.push(b"gtRA");
+        assert_eq!(gen.len(), 12);
+        assert_eq!(gen.slice(0, 12), b"ACGTNNACGTNA");
+        assert_eq!(gen.slice(3, 7), b"TNNA");
+    }
+}
@@ -2,22 +2,103 @@ use std::env;
 use std::process::exit;
 use std::fs::{File, OpenOptions};
 use std::fs;
-use std::io::{prelude::*, BufReader, Write};
-use std::collections::HashMap;
+use std::io::{prelude::*, BufReader, BufWriter, Write};
+use std::collections::{HashMap, HashSet};
 use std::iter;
 use std::path::Path;
 use priority_queue::PriorityQueue;
 use std::cmp;
 
+const BASES: [char; 4] = ['A', 'C', 'G', 'T'];
+// Paths tried from one unitig when looking for a bubble
+const MAX_BUBBLE_PATHS: usize = 1000;
+// Seeds found in more reads than this are repeats and not used
+const MAX_SEED_HITS: usize = 1000;
+// Overlap bases an edit costs when ranking overlaps for the layout
+const EDIT_PENALTY: i64 = 4;
+// Alignment score outside the band
+const INF: u32 = u32::MAX / 2;
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    reads: String,
+    // Minimum overlap for greedy, olc and string graph merging, k-mer size
+    // for de Bruijn mode
+    min_olap: u32,
+    output_stem: String,
+    // greedy (shortest common superstring), dbg (de Bruijn graph), olc
+    // (overlap-layout-consensus with approximate overlaps) or string (string
+    // graph of exact overlaps)
+    mode: String,
+    // de Bruijn mode: k-mers seen fewer times are dropped, and tips and
+    // bubble branches of at most this many k-mers are removed
+    min_count: u32,
+    tip_len: u32,
+    bubble_len: u32,
+    // olc mode: overlaps may have up to max_error edits per overlapping
+    // base. They are seeded by exact matches of seed_len bases and aligned
+    // within band diagonals of the seed
+    max_error: f64,
+    seed_len: u32,
+    band: u32,
+    // greedy mode: also overlap reads with the reverse complements of others
+    both_strands: bool,
+    // GFA version (1 or 2) to also write the graph as <stem>.gfa in
+    gfa: Option<u32>,
+}
+
+// GFA link (or containment, if contained_at is set) between segments,
+// with the CIGAR of the overlap taking from as the reference
+#[derive(Debug, Clone)]
+pub struct Link {
+    from: usize,
+    from_rc: bool,
+    to: usize,
+    to_rc: bool,
+    contained_at: Option<usize>,
+    cigar: String,
+}
+
+// Reads of a contig with their offsets in it
+type Layout = Vec<(usize, usize)>;
+
+// Approximate overlap: the first to_len bases of read to align to read
+// from starting at from_start, with edits differences
+#[derive(Debug, Clone)]
+pub struct Overlap {
+    from: usize,
+    to: usize,
+    from_start: usize,
+    to_len: usize,
+    edits: usize,
+}
+
+// Unitig graph over solid k-mers. Unitig u is followed by v when the last
+// k - 1 bases of u are the first k - 1 bases of v. Both strands are in the
+// graph, so every unitig's reverse complement is also a unitig
+#[derive(Debug, Clone, Default)]
+pub struct DbgGraph {
+    k: usize,
+    unitigs: Vec<Unitig>,
+    // Active unitigs by their first and last k - 1 bases
+    starts: HashMap<String, Vec<usize>>,
+    ends: HashMap<String, Vec<usize>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Unitig {
+    seq: String,
+    // Summed count of the unitig's k-mers
+    cov_sum: u64,
+    removed: bool,
+}
+
+#[allow(clippy::manual_flatten, clippy::needless_range_loop)]
 fn main() {
     let args: Vec<_> = env::args().collect();
-    if args.len() != 4 {
-        println!("Invalid parameters");
-        exit(1);
-    }
-    let reads = &args[1];
-    let min_olap: u32 = args[2].parse().unwrap();
-    let output_stem = &args[3];
+    let opts = parse_args(&args);
+    let min_olap = opts.min_olap;
+    let output_stem = &opts.output_stem;
     // Creating output files
     let output_fa = format!("{}.fa", output_stem);
     let output_edges = format!("{}.edges", output_stem);
@@ -31,7 +112,7 @@ fn main() {
     let mut write_edges = OpenOptions::new().append(true).create(true).open(output_edges).expect("Unable to open file");   
 
     // Read reads into reads vec
-    let genome_file = File::open(reads).expect("Unable to open file");
+    let genome_file = File::open(&opts.reads).expect("Unable to open file");
     let mut reads: Vec<String> = Vec::new();
     let reader = BufReader::new(genome_file);
     let mut insert_line = "".to_string();
@@ -59,56 +140,113 @@ fn main() {
     // Add final line
     reads.push(insert_line);
     println!("[*] Read {} lines from provided filepath into reads", reads.len());
+    if opts.mode == "dbg" {
+        let k = min_olap as usize;
+        println!("[*] Building de Bruijn graph with k = {}", k);
+        let mut graph = DbgGraph::new(&reads, k, opts.min_count);
+        println!("[*] {} unitig(s) from solid k-mers on both strands", graph.active().len());
+        graph.clean(opts.tip_len as usize, opts.bubble_len as usize);
+        let active = graph.active();
+        // One unitig of each reverse complement pair is written, the other
+        // only shows up in edges as that contig on the - strand
+        let written: Vec<usize> = active.iter().copied().filter(|u| graph.twin(*u).is_none_or(|v| v >= *u)).collect();
+        let place = |u: usize| match written.binary_search(&u) {
+            Ok(i) => (i, false),
+            Err(_) => (written.binary_search(&graph.twin(u).unwrap()).unwrap(), true),
+        };
+        let contigs: Vec<String> = written.iter().map(|u| graph.unitigs[*u].seq.clone()).collect();
+        // Edges between output contigs, which overlap by k - 1 bases. Each
+        // is found from both strands and written once
+        let strand = |rc: bool| if rc { '-' } else { '+' };
+        let mut seen: HashSet<Vec<u32>> = HashSet::new();
+        let mut links: Vec<Link> = Vec::new();
+        for u in &active {
+            for v in graph.successors(*u) {
+                let ((from, from_rc), (to, to_rc)) = (place(*u), place(v));
+                if !seen.insert(overlap_key(from as u32, from_rc, to as u32, to_rc)) {
+                    continue;
+                }
+                if let Err(e) = writeln!(write_edges, "{}\t{}\t{}\t{}{}", from, to, k - 1, strand(from_rc), strand(to_rc)) {
+                    eprintln!("Couldn't write to file: {}", e);
+                }
+                links.push(Link { from, from_rc, to, to_rc, contained_at: None, cigar: format!("{}M", k - 1) });
+            }
+        }
+        write_contigs(&mut write_fa, &contigs);
+        if let Some(version) = opts.gfa {
+            write_gfa(&format!("{}.gfa", output_stem), version, &contigs, &links);
+        }
+        return;
+    }
+    if opts.mode == "olc" {
+        let mut links: Vec<Link> = Vec::new();
+        let contigs = assemble_olc(&reads, &opts, &mut write_edges, &mut links);
+        write_contigs(&mut write_fa, &contigs);
+        if let Some(version) = opts.gfa {
+            write_gfa(&format!("{}.gfa", output_stem), version, &reads, &links);
+        }
+        return;
+    }
+    if opts.mode == "string" {
+        let mut links: Vec<Link> = Vec::new();
+        let contigs = assemble_string_graph(&reads, &opts, &mut write_edges, &mut links);
+        write_contigs(&mut write_fa, &contigs);
+        if let Some(version) = opts.gfa {
+            write_gfa(&format!("{}.gfa", output_stem), version, &reads, &links);
+        }
+        return;
+    }
     println!("[*] Computing overlap graph (this takes some time)...");
 
     // Graph datastructure
     // let mut overlap_graph: HashMap<i32, Vec<Vec<i32>>> = HashMap::new();
-    // Union find datastructure
-    let mut uni_find: HashMap<u32, Option<u32>> = HashMap::new();
-    // Suffix/Prefix hash
-    let mut fix_hash: HashMap<String, Vec<u32>> = HashMap::new();
-    // Priority queue to store overlaps by rank
+    // Union find datastructure, with whether each read is reverse
+    // complemented relative to its parent
+    let mut uni_find: HashMap<u32, Option<(u32, bool)>> = HashMap::new();
+    // Suffix/Prefix hash, with whether the read is reverse complemented
+    let mut fix_hash: HashMap<String, Vec<(u32, bool)>> = HashMap::new();
+    // Priority queue to store overlaps by rank. Keys are from, to and
+    // whether each is reverse complemented (never both, see overlap_key)
     let mut ranked_overlaps: PriorityQueue<Vec<u32>, Vec<i32>> = PriorityQueue::new();
+    let strands = if opts.both_strands { vec![false, true] } else { vec![false] };
+    let oriented: Vec<Vec<String>> = reads.iter()
+        .map(|r| strands.iter().map(|rc| if *rc { reverse_complement(r) } else { r.clone() }).collect())
+        .collect();
     // Calculate overlaps
     // Storing prefixes and suffixes as key with value being read id
-    for i in 0..reads.len() {
+    for (i, curr) in oriented.iter().enumerate() {
         // Insert read ids into uni_find
         uni_find.insert(i as u32, None);
-        let curr = &reads[i];
-        let prefix = &curr[..min_olap as usize];
-        let suffix = &curr[(curr.chars().count() - min_olap as usize)..];
-        if fix_hash.get_mut(prefix) == None {
-            fix_hash.insert(prefix.to_string(), vec![i as u32]);
-        } else {
-            fix_hash.get_mut(prefix).unwrap().push(i as u32);
-        }
-        if fix_hash.get_mut(suffix) == None {
-            fix_hash.insert(suffix.to_string(), vec![i as u32]);
-        } else {
-            fix_hash.get_mut(suffix).unwrap().push(i as u32);
+        for (curr, rc) in curr.iter().zip(&strands) {
+            let prefix = &curr[..min_olap as usize];
+            let suffix = &curr[(curr.chars().count() - min_olap as usize)..];
+            fix_hash.entry(prefix.to_string()).or_default().push((i as u32, *rc));
+            fix_hash.entry(suffix.to_string()).or_default().push((i as u32, *rc));
         }
     }
     // Using l-mers to find overlaps
-    for i in 0..reads.len() {
-        let mut l_start = 0;
-        let curr = &reads[i];
-        // Lookup l-mer
-        while l_start + min_olap <= curr.chars().count() as u32 {
-            let l_mer = &curr[l_start as usize..(l_start + min_olap) as usize];
-            if fix_hash.get_mut(l_mer) != None {
-                let v: &mut Vec<u32> = fix_hash.get_mut(l_mer).unwrap();
-                for v_i in v {
-                    if *v_i == i as u32 {
-                        continue;
-                    }
-                    let overlap = find_overlap(curr, &reads[*v_i as usize], min_olap);
-                    // insert into pq
-                    if overlap >= min_olap {
-                        ranked_overlaps.push(vec![i as u32, *v_i], vec![overlap as i32, -(i as i32), -(*v_i as i32)]); 
+    for (i, curr) in oriented.iter().enumerate() {
+        for (curr, rc) in curr.iter().zip(&strands) {
+            let mut l_start = 0;
+            // Lookup l-mer
+            while l_start + min_olap <= curr.chars().count() as u32 {
+                let l_mer = &curr[l_start as usize..(l_start + min_olap) as usize];
+                if let Some(v) = fix_hash.get(l_mer) {
+                    for (v_i, v_rc) in v {
+                        if *v_i == i as u32 {
+                            continue;
+                        }
+                        let overlap = find_overlap(curr, &oriented[*v_i as usize][*v_rc as usize], min_olap);
+                        // insert into pq
+                        if overlap >= min_olap {
+                            let key = overlap_key(i as u32, *rc, *v_i, *v_rc);
+                            let rank = overlap_rank(overlap, &key);
+                            ranked_overlaps.push(key, rank);
+                        }
                     }
                 }
+                l_start += 1;
             }
-            l_start += 1;
         }
     }
     
@@ -117,69 +255,67 @@ fn main() {
     println!("First item in priority queue is: {:?}", ranked_overlaps.peek());
     // Work through all pq entries
     let pq = ranked_overlaps.clone();
+    // The reads are merged in place, so the GFA keeps a copy of them
+    let segments = if opts.gfa.is_some() { reads.clone() } else { Vec::new() };
+    let mut links: Vec<Link> = Vec::new();
     println!("[*] Starting priority queue: ");
     for x in pq.into_sorted_iter() {
-        if let Err(e) = writeln!(write_edges, "{}\t{}\t{}", x.0[0], x.0[1], x.1[0]) {
+        // Strands are only written when reverse complements are considered
+        let strand = |rc: u32| if rc == 1 { '-' } else { '+' };
+        let written = if opts.both_strands {
+            writeln!(write_edges, "{}\t{}\t{}\t{}{}", x.0[0], x.0[1], x.1[0], strand(x.0[2]), strand(x.0[3]))
+        } else {
+            writeln!(write_edges, "{}\t{}\t{}", x.0[0], x.0[1], x.1[0])
+        };
+        if let Err(e) = written {
             eprintln!("Couldn't write to file: {}", e);
         }
+        if opts.gfa.is_some() {
+            links.push(Link {
+                from: x.0[0] as usize,
+                from_rc: x.0[2] == 1,
+                to: x.0[1] as usize,
+                to_rc: x.0[3] == 1,
+                contained_at: None,
+                cigar: format!("{}M", x.1[0]),
+            });
+        }
         //println!("{:?}", x)
     } 
 
-    while ranked_overlaps.peek() != None {
+    while ranked_overlaps.peek().is_some() {
         let curr = ranked_overlaps.pop().unwrap();
-        let mut from = curr.0[0];
-        let mut to = curr.0[1];
         println!("Popping element: {:?}", curr);
-        // Combine strings and save to reads
-        // Case: Two fresh strings
-        if uni_find.get(&from) == None && uni_find.get(&to) == None {
-            let suffix = &reads[from as usize];
-            let prefix = &reads[to as usize][(curr.1[0] as usize)..];
-            let extended = format!("{}{}", suffix, prefix);
+        // Combine the strings holding both reads, each turned so its read
+        // has the strand the overlap asks for
+        let (from, from_flip) = find_oriented(&uni_find, curr.0[0]);
+        let (to, to_flip) = find_oriented(&uni_find, curr.0[1]);
+        if from == to {
+            continue;
+        }
+        let from_flip = from_flip != (curr.0[2] == 1);
+        let to_flip = to_flip != (curr.0[3] == 1);
+        let suffix = if from_flip { reverse_complement(&reads[from as usize]) } else { reads[from as usize].clone() };
+        let prefix = if to_flip { reverse_complement(&reads[to as usize]) } else { reads[to as usize].clone() };
+        let check_overlap = find_overlap(&suffix, &prefix, min_olap);
+        if curr.1[0] <= check_overlap as i32 {
+            let extended = format!("{}{}", suffix, &prefix[(curr.1[0] as usize)..]);
             let new_pos = cmp::min(from, to);
             let old_pos = cmp::max(from, to);
-            uni_find.insert(old_pos, Some(new_pos));
+            // The combined string is stored on the strand new_pos was on, so
+            // only old_pos may change strand relative to it
+            let (new_flip, old_flip) = if new_pos == from { (from_flip, to_flip) } else { (to_flip, from_flip) };
+            uni_find.insert(old_pos, Some((new_pos, old_flip != new_flip)));
             // Update combined into reads with smallest rank
-            reads[new_pos as usize] = extended;
+            reads[new_pos as usize] = if new_flip { reverse_complement(&extended) } else { extended };
             // Delete j read since it will now be referenced by i read
             // Attempt to remove the back edge if it exists
-            ranked_overlaps.remove(&vec![curr.0[1], curr.0[0]]);
+            ranked_overlaps.remove(&vec![curr.0[1], curr.0[0], curr.0[2], curr.0[3]]);
             reads[old_pos as usize] = "".to_string();
-        } else { // Case either string has already been merged
-            while uni_find.get(&from) != None {
-                if *(uni_find.get(&from).unwrap()) == None {
-                    break;
-                }
-                from = uni_find.get(&from).unwrap().unwrap();
-            }
-            while uni_find.get(&to) != None {
-                if *(uni_find.get(&to).unwrap()) == None {
-                    break;
-                }
-                to = uni_find.get(&to).unwrap().unwrap();
-            }
-            if from == to {
-                continue;
-            }
-            let check_overlap = find_overlap(&reads[from as usize], &reads[to as usize], min_olap);
-            if curr.1[0] <= check_overlap as i32 {
-                let suffix = &reads[from as usize];
-                let prefix = &reads[to as usize][(curr.1[0] as usize)..];
-                let extended = format!("{}{}", suffix, prefix);
-                let new_pos = cmp::min(from, to);
-                let old_pos = cmp::max(from, to);
-                uni_find.insert(old_pos, Some(new_pos));
-                // Update combined into reads with smallest rank
-                reads[new_pos as usize] = extended;
-                // Delete j read since it will now be referenced by i read
-                // Attempt to remove the back edge if it exists
-                ranked_overlaps.remove(&vec![curr.0[1], curr.0[0]]);
-                reads[old_pos as usize] = "".to_string();
-            } else {
-                if check_overlap >= min_olap {
-                    ranked_overlaps.push(vec![from as u32, to as u32], vec![check_overlap as i32, -(from as i32), -(to as i32)]);
-                }
-            }
+        } else if check_overlap >= min_olap {
+            let key = overlap_key(from, from_flip, to, to_flip);
+            let rank = overlap_rank(check_overlap, &key);
+            ranked_overlaps.push(key, rank);
         }
 
         // let pq = ranked_overlaps.clone();
@@ -203,9 +339,1019 @@ fn main() {
         }
     }
     println!("Generated islands: {}", island_count);
+    if let Some(version) = opts.gfa {
+        write_gfa(&format!("{}.gfa", output_stem), version, &segments, &links);
+    }
+}
+
+// Positional args are reads min_olap output_stem; options may appear anywhere
+pub fn parse_args(args: &[String]) -> Options {
+    let mut opts = Options {
+        mode: "greedy".to_string(),
+        min_count: 2,
+        max_error: 0.1,
+        seed_len: 15,
+        band: 10,
+        ..Default::default()
+    };
+    let mut positional: Vec<&String> = Vec::new();
+    let mut tip_len: Option<u32> = None;
+    let mut bubble_len: Option<u32> = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--mode" if i + 1 < args.len() => {
+                opts.mode = args[i + 1].clone();
+                i += 1;
+            }
+            "--min-count" if i + 1 < args.len() => {
+                opts.min_count = parse_num(&args[i + 1]);
+                i += 1;
+            }
+            "--tip-len" if i + 1 < args.len() => {
+                tip_len = Some(parse_num(&args[i + 1]));
+                i += 1;
+            }
+            "--bubble-len" if i + 1 < args.len() => {
+                bubble_len = Some(parse_num(&args[i + 1]));
+                i += 1;
+            }
+            "--max-error" if i + 1 < args.len() => {
+                opts.max_error = parse_num(&args[i + 1]);
+                i += 1;
+            }
+            "--seed-len" if i + 1 < args.len() => {
+                opts.seed_len = parse_num(&args[i + 1]);
+                i += 1;
+            }
+            "--band" if i + 1 < args.len() => {
+                opts.band = parse_num(&args[i + 1]);
+                i += 1;
+            }
+            "--rc" => opts.both_strands = true,
+            "--gfa" if i + 1 < args.len() => {
+                opts.gfa = Some(parse_num(&args[i + 1]));
+                i += 1;
+            }
+            _ => positional.push(&args[i]),
+        }
+        i += 1;
+    }
+    if positional.len() != 3 || !["greedy", "dbg", "olc", "string"].contains(&opts.mode.as_str()) {
+        invalid_parameters();
+    }
+    opts.reads = positional[0].clone();
+    opts.min_olap = parse_num(positional[1]);
+    opts.output_stem = positional[2].clone();
+    if (opts.mode == "dbg" && opts.min_olap < 2) || (opts.mode == "string" && opts.min_olap == 0) || opts.seed_len == 0 || !(0.0..1.0).contains(&opts.max_error)
+        || (opts.both_strands && opts.mode != "greedy") || opts.gfa.is_some_and(|v| v != 1 && v != 2) {
+        invalid_parameters();
+    }
+    // Tips and bubble branches default to twice k
+    opts.tip_len = tip_len.unwrap_or(2 * opts.min_olap);
+    opts.bubble_len = bubble_len.unwrap_or(2 * opts.min_olap);
+    opts
+}
+
+pub fn parse_num<T: std::str::FromStr>(arg: &str) -> T {
+    match arg.parse() {
+        Ok(num) => num,
+        Err(_) => invalid_parameters(),
+    }
+}
+
+pub fn invalid_parameters() -> ! {
+    println!("Invalid parameters");
+    exit(1);
+}
+
+// Writes the non-empty sequences as >id:len records
+pub fn write_contigs(write_fa: &mut File, contigs: &[String]) {
+    let mut island_count = 0;
+    for contig in contigs.iter().filter(|c| !c.is_empty()) {
+        if let Err(e) = writeln!(write_fa, ">{}:{}", island_count, contig.chars().count()) {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+        island_count += 1;
+        if let Err(e) = writeln!(write_fa, "{}", contig) {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+    }
+    println!("Generated islands: {}", island_count);
+}
+
+impl DbgGraph {
+    // One unitig per distinct k-mer seen at least min_count times on either
+    // strand, and one for its reverse complement, then compacted
+    pub fn new(reads: &[String], k: usize, min_count: u32) -> DbgGraph {
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        // k-mers with anything but ACGT are skipped
+        for piece in reads.iter().flat_map(|read| read.split(|c| !BASES.contains(&c))) {
+            for start in 0..(piece.len() + 1).saturating_sub(k) {
+                *counts.entry(&piece[start..start + k]).or_insert(0) += 1;
+            }
+        }
+        // A k-mer and its reverse complement are counted together; each is
+        // added by itself if it was seen, else by the other
+        let mut solid: Vec<(String, u32)> = Vec::new();
+        for (kmer, count) in &counts {
+            let rc = reverse_complement(kmer);
+            let rc_count = if rc == *kmer { 0 } else { counts.get(rc.as_str()).copied().unwrap_or(0) };
+            if count + rc_count < min_count {
+                continue;
+            }
+            if rc_count == 0 && rc != *kmer {
+                solid.push((rc, count + rc_count));
+            }
+            solid.push((kmer.to_string(), count + rc_count));
+        }
+        // Sorted so unitig ids, and so output order, do not depend on hashing
+        solid.sort_unstable();
+        let mut graph = DbgGraph { k, ..Default::default() };
+        for (kmer, count) in solid {
+            graph.add(Unitig { seq: kmer, cov_sum: count as u64, removed: false });
+        }
+        graph.compact();
+        graph
+    }
+
+    fn add(&mut self, unitig: Unitig) {
+        let id = self.unitigs.len();
+        self.starts.entry(self.head(&unitig.seq).to_string()).or_default().push(id);
+        self.ends.entry(self.tail(&unitig.seq).to_string()).or_default().push(id);
+        self.unitigs.push(unitig);
+    }
+
+    fn head<'a>(&self, seq: &'a str) -> &'a str {
+        &seq[..self.k - 1]
+    }
+
+    fn tail<'a>(&self, seq: &'a str) -> &'a str {
+        &seq[seq.len() - (self.k - 1)..]
+    }
+
+    // Ids of unitigs still in the graph, in increasing order
+    pub fn active(&self) -> Vec<usize> {
+        (0..self.unitigs.len()).filter(|u| !self.unitigs[*u].removed).collect()
+    }
+
+    pub fn successors(&self, u: usize) -> Vec<usize> {
+        let tail = self.tail(&self.unitigs[u].seq);
+        self.starts.get(tail).cloned().unwrap_or_default()
+    }
+
+    pub fn predecessors(&self, u: usize) -> Vec<usize> {
+        let head = self.head(&self.unitigs[u].seq);
+        self.ends.get(head).cloned().unwrap_or_default()
+    }
+
+    // The unitig spelling u's reverse complement, which is u itself for a
+    // palindrome
+    pub fn twin(&self, u: usize) -> Option<usize> {
+        let rc = reverse_complement(&self.unitigs[u].seq);
+        self.starts.get(self.head(&rc))?.iter().copied().find(|v| self.unitigs[*v].seq == rc)
+    }
+
+    // Number of k-mers in unitig u
+    pub fn kmers(&self, u: usize) -> usize {
+        self.unitigs[u].seq.len() - self.k + 1
+    }
+
+    pub fn coverage(&self, u: usize) -> f64 {
+        self.unitigs[u].cov_sum as f64 / self.kmers(u) as f64
+    }
+
+    fn remove(&mut self, u: usize) {
+        let seq = std::mem::take(&mut self.unitigs[u].seq);
+        self.starts.get_mut(self.head(&seq)).unwrap().retain(|v| *v != u);
+        self.ends.get_mut(self.tail(&seq)).unwrap().retain(|v| *v != u);
+        self.unitigs[u].seq = seq;
+        self.unitigs[u].removed = true;
+    }
+
+    // Removes u along with its reverse complement, so both strands are
+    // cleaned the same way
+    fn remove_both(&mut self, u: usize) {
+        let twin = self.twin(u);
+        self.remove(u);
+        if let Some(v) = twin.filter(|v| *v != u) {
+            self.remove(v);
+        }
+    }
+
+    // Joins every unitig to its successor while the link between them is the
+    // only way out of the first and the only way into the second
+    pub fn compact(&mut self) -> bool {
+        let mut changed = false;
+        for u in 0..self.unitigs.len() {
+            while !self.unitigs[u].removed {
+                let next = self.successors(u);
+                if next.len() != 1 || next[0] == u || self.predecessors(next[0]).len() != 1 {
+                    break;
+                }
+                let v = next[0];
+                let (seq, cov_sum) = (self.unitigs[v].seq.clone(), self.unitigs[v].cov_sum);
+                self.remove(v);
+                let old_tail = self.tail(&self.unitigs[u].seq).to_string();
+                self.ends.get_mut(&old_tail).unwrap().retain(|w| *w != u);
+                self.unitigs[u].seq.push_str(&seq[self.k - 1..]);
+                self.unitigs[u].cov_sum += cov_sum;
+                self.ends.entry(self.tail(&seq).to_string()).or_default().push(u);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Removes dead-end unitigs of at most tip_len k-mers that join a
+    // branching unitig, and isolated ones as short
+    pub fn clip_tips(&mut self, tip_len: usize) -> bool {
+        let mut changed = false;
+        for u in self.active() {
+            if self.unitigs[u].removed || self.kmers(u) > tip_len {
+                continue;
+            }
+            let (pred, succ) = (self.predecessors(u), self.successors(u));
+            let is_tip = match (pred.is_empty(), succ.is_empty()) {
+                (true, false) => succ.iter().any(|v| self.predecessors(*v).len() > 1),
+                (false, true) => pred.iter().any(|v| self.successors(*v).len() > 1),
+                (true, true) => true,
+                (false, false) => false,
+            };
+            if is_tip {
+                self.remove_both(u);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Bubbles: two paths of at most bubble_len k-mers leaving the same
+    // unitig and meeting again. Unitigs only on the less covered path, with
+    // one way in and one way out, are removed. Paths are searched depth
+    // first, giving up after MAX_BUBBLE_PATHS
+    pub fn pop_bubbles(&mut self, bubble_len: usize) -> bool {
+        let mut changed = false;
+        for u in self.active() {
+            if self.unitigs[u].removed || self.successors(u).len() < 2 {
+                continue;
+            }
+            // Unitigs between u and each unitig reached, for the first path found
+            let mut reached: HashMap<usize, Vec<usize>> = HashMap::new();
+            let mut stack: Vec<Vec<usize>> = self.successors(u).into_iter().map(|v| vec![v]).collect();
+            let mut doomed: Vec<usize> = Vec::new();
+            let mut searched = 0;
+            while let Some(path) = stack.pop() {
+                searched += 1;
+                if searched > MAX_BUBBLE_PATHS {
+                    break;
+                }
+                let (w, inner) = path.split_last().unwrap();
+                if let Some(other) = reached.get(w) {
+                    let (strong, weak) = if self.path_coverage(other) >= self.path_coverage(inner) {
+                        (other.as_slice(), inner)
+                    } else {
+                        (inner, other.as_slice())
+                    };
+                    doomed = weak.iter().copied().filter(|x| !strong.contains(x)
+                        && self.predecessors(*x).len() == 1 && self.successors(*x).len() == 1).collect();
+                    if !doomed.is_empty() {
+                        break;
+                    }
+                    continue;
+                }
+                reached.insert(*w, inner.to_vec());
+                if *w == u || path.iter().map(|x| self.kmers(*x)).sum::<usize>() > bubble_len {
+                    continue;
+                }
+                for x in self.successors(*w) {
+                    if !path.contains(&x) {
+                        let mut longer = path.clone();
+                        longer.push(x);
+                        stack.push(longer);
+                    }
+                }
+            }
+            for x in doomed {
+                if !self.unitigs[x].removed {
+                    self.remove_both(x);
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    // Mean k-mer count over a path's unitigs; an empty path (a direct link)
+    // always wins
+    fn path_coverage(&self, path: &[usize]) -> f64 {
+        if path.is_empty() {
+            return f64::INFINITY;
+        }
+        let cov_sum: u64 = path.iter().map(|u| self.unitigs[*u].cov_sum).sum();
+        let kmers: usize = path.iter().map(|u| self.kmers(*u)).sum();
+        cov_sum as f64 / kmers as f64
+    }
+
+    // Clips tips and pops bubbles, compacting after each round, until the
+    // graph stops changing
+    pub fn clean(&mut self, tip_len: usize, bubble_len: usize) {
+        let mut round = 0;
+        loop {
+            let tips = self.clip_tips(tip_len);
+            let bubbles = self.pop_bubbles(bubble_len);
+            if !tips && !bubbles {
+                break;
+            }
+            self.compact();
+            round += 1;
+            println!("[*] Cleaning round {}: {} unitig(s) left", round, self.active().len());
+        }
+    }
+}
+
+// Overlap-layout-consensus: approximate overlaps between reads, a greedy
+// best-first layout into chains of reads, and a majority consensus along
+// each chain. Contained reads only take part in the consensus
+pub fn assemble_olc(reads: &[String], opts: &Options, write_edges: &mut File, links: &mut Vec<Link>) -> Vec<String> {
+    let reads: Vec<&[u8]> = reads.iter().map(|r| r.as_bytes()).collect();
+    let n = reads.len();
+    let (overlaps, contained) = find_approx_overlaps(&reads, opts);
+    let found = overlaps.len();
+    // Contained reads are left out of the layout, as are overlaps implied
+    // by two others, which would let it skip over reads
+    let overlaps: Vec<Overlap> = overlaps.into_iter()
+        .filter(|o| contained[o.from].is_none() && contained[o.to].is_none())
+        .collect();
+    let mut overlaps = transitive_reduction(n, overlaps, opts.band as usize);
+    println!("[*] {} approximate overlap(s), {} contained read(s), {} overlap(s) left after transitive reduction", found,
+        contained.iter().filter(|c| c.is_some()).count(), overlaps.len());
+    // Best overlaps first, scored by length less EDIT_PENALTY per edit so
+    // reads carrying many errors are joined last
+    overlaps.sort_by_key(|o| (cmp::Reverse(o.to_len as i64 - EDIT_PENALTY * o.edits as i64), o.from, o.to));
+    for o in &overlaps {
+        if let Err(e) = writeln!(write_edges, "{}\t{}\t{}", o.from, o.to, o.to_len) {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+    }
+    // Overlaps and containments for the GFA, realigned for their CIGARs
+    if opts.gfa.is_some() {
+        for o in &overlaps {
+            let (from, to) = (reads[o.from], &reads[o.to][..o.to_len]);
+            let cigar = align_cigar(to, &from[o.from_start..], opts.band as usize);
+            links.push(Link { from: o.from, from_rc: false, to: o.to, to_rc: false, contained_at: None, cigar });
+        }
+        for (r, host) in contained.iter().enumerate() {
+            if let Some((host, offset)) = host {
+                let window = &reads[*host][*offset..(offset + reads[r].len()).min(reads[*host].len())];
+                let cigar = align_cigar(reads[r], window, opts.band as usize);
+                links.push(Link { from: *host, from_rc: false, to: r, to_rc: false, contained_at: Some(*offset), cigar });
+            }
+        }
+    }
+
+    // Layout: each read gets at most one successor and one predecessor, and
+    // links closing a cycle are skipped (union-find over chains)
+    let mut next: Vec<Option<&Overlap>> = vec![None; n];
+    let mut has_prev = vec![false; n];
+    let mut chain: Vec<usize> = (0..n).collect();
+    for o in &overlaps {
+        if next[o.from].is_some() || has_prev[o.to] {
+            continue;
+        }
+        let (from_chain, to_chain) = (find_root(&mut chain, o.from), find_root(&mut chain, o.to));
+        if from_chain == to_chain {
+            continue;
+        }
+        chain[to_chain] = from_chain;
+        next[o.from] = Some(o);
+        has_prev[o.to] = true;
+    }
+    // Contained reads by the read holding them, with their offset in it
+    let mut inside: Vec<Layout> = vec![Vec::new(); n];
+    for (r, host) in contained.iter().enumerate() {
+        if let Some((mut host, mut offset)) = host {
+            while let Some((outer, outer_offset)) = contained[host] {
+                host = outer;
+                offset += outer_offset;
+            }
+            inside[host].push((r, offset));
+        }
+    }
+
+    // Each chain as a draft sequence and every read's offset in it
+    let mut chains: Vec<(Vec<u8>, Layout)> = Vec::new();
+    for first in 0..n {
+        // Empty reads (an empty record, or the one read from an empty file)
+        // make no contig
+        if contained[first].is_some() || has_prev[first] || reads[first].is_empty() {
+            continue;
+        }
+        // Draft sequence from the chain, each read replacing the tail of the
+        // one before from where they overlap, and every read's offset in it
+        let mut draft: Vec<u8> = Vec::new();
+        let mut layout: Layout = Vec::new();
+        let (mut r, mut offset) = (first, 0);
+        loop {
+            draft.truncate(offset);
+            draft.extend_from_slice(reads[r]);
+            layout.push((r, offset));
+            layout.extend(inside[r].iter().map(|(c, c_offset)| (*c, offset + c_offset)));
+            match next[r] {
+                Some(o) => {
+                    offset += o.from_start;
+                    r = o.to;
+                }
+                None => break,
+            }
+        }
+        chains.push((draft, layout));
+    }
+
+    // Chains lying within a longer one, placed there by an overlap between
+    // them, are reads the layout went around (often ones with many errors).
+    // They are folded into the longer chain's consensus
+    chains.sort_by_key(|(draft, _)| cmp::Reverse(draft.len()));
+    let mut home: Vec<Option<(usize, i64)>> = vec![None; n];
+    for (c, (_, layout)) in chains.iter().enumerate() {
+        for (r, offset) in layout {
+            home[*r] = Some((c, *offset as i64));
+        }
+    }
+    let mut kept = vec![true; chains.len()];
+    for c in 1..chains.len() {
+        let fold = overlaps.iter().find_map(|o| {
+            let ((from_chain, from_offset), (to_chain, to_offset)) = (home[o.from]?, home[o.to]?);
+            let (host, shift) = if to_chain == c && from_chain != c {
+                (from_chain, from_offset + o.from_start as i64 - to_offset)
+            } else if from_chain == c && to_chain != c {
+                (to_chain, to_offset - from_offset - o.from_start as i64)
+            } else {
+                return None;
+            };
+            let fits = shift >= 0 && shift as usize + chains[c].0.len() <= chains[host].0.len() + opts.band as usize;
+            (host < c && kept[host] && fits).then_some((host, shift))
+        });
+        if let Some((host, shift)) = fold {
+            kept[c] = false;
+            let layout = std::mem::take(&mut chains[c].1);
+            for (r, offset) in layout {
+                home[r] = Some((host, offset as i64 + shift));
+                chains[host].1.push((r, offset + shift as usize));
+            }
+        }
+    }
+    println!("[*] {} chain(s) laid out, {} folded into longer ones", chains.len(), kept.iter().filter(|k| !**k).count());
+    chains.iter().zip(kept).filter(|(_, kept)| *kept)
+        .map(|((draft, layout), _)| consensus(draft, layout, &reads, opts.band as usize))
+        .collect()
+}
+
+pub fn find_root(parent: &mut [usize], mut x: usize) -> usize {
+    while parent[x] != x {
+        parent[x] = parent[parent[x]];
+        x = parent[x];
+    }
+    x
+}
+
+// Overlaps from each read to reads starting inside it, and for each read
+// the read containing it (if any) with its offset there. Each pair is
+// aligned once, along the diagonal most of its seeds agree on
+pub fn find_approx_overlaps(reads: &[&[u8]], opts: &Options) -> (Vec<Overlap>, Vec<Option<(usize, usize)>>) {
+    let k = opts.seed_len as usize;
+    let mut seeds: HashMap<&[u8], Vec<(usize, usize)>> = HashMap::new();
+    for (r, read) in reads.iter().enumerate() {
+        for pos in 0..(read.len() + 1).saturating_sub(k) {
+            seeds.entry(&read[pos..pos + k]).or_default().push((r, pos));
+        }
+    }
+    let mut overlaps: Vec<Overlap> = Vec::new();
+    let mut contained: Vec<Option<(usize, usize)>> = vec![None; reads.len()];
+    for (a, read) in reads.iter().enumerate() {
+        // Seed hits by other read and diagonal (where it starts in this read)
+        let mut diagonals: HashMap<usize, HashMap<usize, u32>> = HashMap::new();
+        for pos in 0..(read.len() + 1).saturating_sub(k) {
+            let hits = &seeds[&read[pos..pos + k]];
+            if hits.len() > MAX_SEED_HITS {
+                continue;
+            }
+            for (b, b_pos) in hits {
+                if *b != a && pos >= *b_pos {
+                    *diagonals.entry(*b).or_default().entry(pos - b_pos).or_insert(0) += 1;
+                }
+            }
+        }
+        let mut candidates: Vec<(usize, usize)> = diagonals.into_iter()
+            .map(|(b, diags)| (b, diags.into_iter().max_by_key(|(d, count)| (*count, cmp::Reverse(*d))).unwrap().0))
+            .collect();
+        candidates.sort_unstable();
+        for (b, diag) in candidates {
+            let Some((start, end, edits, inside)) = overlap_align(read, reads[b], diag, opts.band as usize) else {
+                continue;
+            };
+            let (a_len, b_len) = (read.len(), reads[b].len());
+            if inside {
+                // Reads covering the same stretch: the shorter one (or the
+                // higher id) is held by the other, so containment never loops
+                if edits as f64 <= opts.max_error * b_len as f64 {
+                    if b_len < a_len || (b_len == a_len && b > a) {
+                        contained[b].get_or_insert((a, start));
+                    } else {
+                        contained[a].get_or_insert((b, 0));
+                    }
+                }
+            } else if end >= opts.min_olap as usize && end < b_len && edits as f64 <= opts.max_error * end as f64 {
+                overlaps.push(Overlap { from: a, to: b, from_start: start, to_len: end, edits });
+            }
+        }
+    }
+    (overlaps, contained)
+}
 
+// Aligns b to the part of a starting near diag, letting alignment begin
+// anywhere in a and end anywhere in b, with edit distance scoring kept to
+// band diagonals (only those cells are stored). Returns where b starts in
+// a, how much of b aligns, the edits, and whether b ends inside a
+// (contained) rather than past its end
+pub fn overlap_align(a: &[u8], b: &[u8], diag: usize, band: usize) -> Option<(usize, usize, usize, bool)> {
+    let a_from = diag.saturating_sub(band);
+    let a = &a[a_from..];
+    // Cell (i, j) lies on diagonal j - i + shift, kept within band of 0
+    let shift = diag - a_from;
+    let (n, m) = (a.len(), b.len());
+    let dp = Banded::new(n, m, -(shift as isize) - band as isize, -(shift as isize) + band as isize);
+    let dp = dp.fill(|i, j, dp| {
+        if j == 0 {
+            // Skipping the start of a is free
+            0
+        } else if i == 0 {
+            j as u32
+        } else {
+            let sub = dp.get(i - 1, j - 1) + (a[i - 1] != b[j - 1]) as u32;
+            sub.min(dp.get(i - 1, j) + 1).min(dp.get(i, j - 1) + 1)
+        }
+    });
+    // Best end with all of b aligned (b inside a) and with all of a aligned
+    // (b running past the end of a)
+    let inside_end = (0..=n).min_by_key(|i| (dp.get(*i, m), *i)).unwrap();
+    let past_end = (0..=m).min_by_key(|j| (dp.get(n, *j), cmp::Reverse(*j))).unwrap();
+    let (i, j, inside) = if dp.get(inside_end, m) <= dp.get(n, past_end) {
+        (inside_end, m, true)
+    } else {
+        (n, past_end, false)
+    };
+    let edits = dp.get(i, j);
+    if edits >= INF || j == 0 {
+        return None;
+    }
+    // Trace back to where b starts
+    let (mut ti, mut tj) = (i, j);
+    while tj > 0 {
+        let here = dp.get(ti, tj);
+        if ti > 0 && dp.get(ti - 1, tj - 1) + (a[ti - 1] != b[tj - 1]) as u32 == here {
+            ti -= 1;
+            tj -= 1;
+        } else if ti > 0 && dp.get(ti - 1, tj) + 1 == here {
+            ti -= 1;
+        } else {
+            tj -= 1;
+        }
+    }
+    Some((a_from + ti, j, edits as usize, inside))
 }
 
+// Aligns all of read somewhere inside window (edit distance, free ends in
+// the window), keeping within band diagonals of the read starting at the
+// window start or ending at its end. Returns where it starts in window and
+// the operations in read order: = and X for aligned bases, I for read bases
+// not in the window, D for window bases missing from the read
+pub fn fit_align(read: &[u8], window: &[u8], band: usize) -> (usize, Vec<u8>) {
+    let (n, m) = (read.len(), window.len());
+    // Cell (i, j) lies on diagonal j - i
+    let slack = m as isize - n as isize;
+    let dp = Banded::new(n, m, slack.min(0) - band as isize, slack.max(0) + band as isize);
+    let dp = dp.fill(|i, j, dp| {
+        if i == 0 {
+            0
+        } else if j == 0 {
+            i as u32
+        } else {
+            let sub = dp.get(i - 1, j - 1) + (read[i - 1] != window[j - 1]) as u32;
+            sub.min(dp.get(i - 1, j) + 1).min(dp.get(i, j - 1) + 1)
+        }
+    });
+    let mut j = (0..=m).min_by_key(|j| (dp.get(n, *j), *j)).unwrap();
+    let mut i = n;
+    let mut ops: Vec<u8> = Vec::with_capacity(n);
+    while i > 0 {
+        let here = dp.get(i, j);
+        if j > 0 && dp.get(i - 1, j - 1) + (read[i - 1] != window[j - 1]) as u32 == here {
+            ops.push(if read[i - 1] == window[j - 1] { b'=' } else { b'X' });
+            i -= 1;
+            j -= 1;
+        } else if dp.get(i - 1, j) + 1 == here {
+            ops.push(b'I');
+            i -= 1;
+        } else {
+            ops.push(b'D');
+            j -= 1;
+        }
+    }
+    ops.reverse();
+    (j, ops)
+}
+
+// Alignment matrix over rows 0..=n and columns 0..=m storing only the
+// cells on diagonals j - i from lo to hi; the others read as INF
+pub struct Banded {
+    rows: usize,
+    cols: usize,
+    lo: isize,
+    hi: isize,
+    cells: Vec<u32>,
+}
+
+impl Banded {
+    pub fn new(n: usize, m: usize, lo: isize, hi: isize) -> Banded {
+        Banded { rows: n + 1, cols: m + 1, lo, hi, cells: vec![INF; (n + 1) * (hi - lo + 1) as usize] }
+    }
+
+    fn cell(&self, i: usize, j: usize) -> Option<usize> {
+        let d = j as isize - i as isize;
+        if i >= self.rows || j >= self.cols || d < self.lo || d > self.hi {
+            return None;
+        }
+        Some(i * (self.hi - self.lo + 1) as usize + (d - self.lo) as usize)
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> u32 {
+        self.cell(i, j).map_or(INF, |c| self.cells[c])
+    }
+
+    // Fills the band row by row, left to right, from score(i, j, filled so far)
+    pub fn fill(mut self, score: impl Fn(usize, usize, &Banded) -> u32) -> Banded {
+        for i in 0..self.rows {
+            let j_lo = (i as isize + self.lo).max(0);
+            let j_hi = (i as isize + self.hi).min(self.cols as isize - 1);
+            for j in j_lo..=j_hi {
+                let (j, c) = (j as usize, self.cell(i, j as usize).unwrap());
+                self.cells[c] = score(i, j, &self);
+            }
+        }
+        self
+    }
+}
+
+// Majority consensus of the reads placed on draft at the given offsets.
+// Each read is aligned within band bases of its offset; a column takes the
+// most voted base or is dropped if most reads skip it, and an insertion is
+// kept when most reads over that point carry it
+pub fn consensus(draft: &[u8], layout: &[(usize, usize)], reads: &[&[u8]], band: usize) -> String {
+    if draft.is_empty() {
+        return String::new();
+    }
+    // Votes for A, C, G, T and a gap in each draft column
+    let mut votes: Vec<[u32; 5]> = vec![[0; 5]; draft.len()];
+    // Inserted sequences voted for before each column
+    let mut inserts: Vec<HashMap<Vec<u8>, u32>> = vec![HashMap::new(); draft.len() + 1];
+    for (r, offset) in layout {
+        let read = reads[*r];
+        let lo = offset.saturating_sub(band);
+        let hi = (offset + read.len() + band).min(draft.len());
+        let (start, ops) = fit_align(read, &draft[lo..hi], band);
+        let (mut col, mut pos) = (lo + start, 0);
+        let mut inserted: Vec<u8> = Vec::new();
+        for op in ops {
+            if op == b'I' {
+                inserted.push(read[pos]);
+                pos += 1;
+                continue;
+            }
+            if !inserted.is_empty() {
+                *inserts[col].entry(std::mem::take(&mut inserted)).or_insert(0) += 1;
+            }
+            if op == b'D' {
+                votes[col][4] += 1;
+            } else {
+                if let Some(base) = BASES.iter().position(|b| *b as u8 == read[pos]) {
+                    votes[col][base] += 1;
+                }
+                pos += 1;
+            }
+            col += 1;
+        }
+        if !inserted.is_empty() {
+            *inserts[col].entry(inserted).or_insert(0) += 1;
+        }
+    }
+    let mut contig = String::with_capacity(draft.len());
+    for col in 0..=draft.len() {
+        let depth: u32 = votes[col.min(draft.len().saturating_sub(1))].iter().sum();
+        let best_insert = inserts[col].iter().max_by_key(|(seq, count)| (**count, cmp::Reverse((*seq).clone())));
+        if let Some((seq, count)) = best_insert {
+            if 2 * count > depth {
+                contig.push_str(&String::from_utf8_lossy(seq));
+            }
+        }
+        if col == draft.len() {
+            break;
+        }
+        let best = (0..5).max_by_key(|v| (votes[col][*v], cmp::Reverse(*v))).unwrap();
+        if votes[col][best] == 0 {
+            contig.push(draft[col] as char);
+        } else if best < 4 {
+            contig.push(BASES[best]);
+        }
+    }
+    contig
+}
+
+// String graph (Myers 2005): contained reads and transitive overlaps are
+// dropped, and each unambiguous path of the remaining overlaps (one way out
+// of every read but the last, one way into every read but the first) is
+// spelled out as a contig. Contigs end at branches rather than guessing
+// through repeats
+pub fn assemble_string_graph(reads: &[String], opts: &Options, write_edges: &mut File, links: &mut Vec<Link>) -> Vec<String> {
+    let reads: Vec<&[u8]> = reads.iter().map(|r| r.as_bytes()).collect();
+    let n = reads.len();
+    let (overlaps, contained) = find_exact_overlaps(&reads, opts.min_olap as usize);
+    let found = overlaps.len();
+    let overlaps: Vec<Overlap> = overlaps.into_iter()
+        .filter(|o| contained[o.from].is_none() && contained[o.to].is_none())
+        .collect();
+    let overlaps = transitive_reduction(n, overlaps, 0);
+    println!("[*] {} exact overlap(s), {} contained read(s), {} overlap(s) left after transitive reduction", found,
+        contained.iter().filter(|c| c.is_some()).count(), overlaps.len());
+    for o in &overlaps {
+        if let Err(e) = writeln!(write_edges, "{}\t{}\t{}", o.from, o.to, o.to_len) {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+    }
+    if opts.gfa.is_some() {
+        for o in &overlaps {
+            links.push(Link { from: o.from, from_rc: false, to: o.to, to_rc: false, contained_at: None, cigar: format!("{}M", o.to_len) });
+        }
+        for (r, host) in contained.iter().enumerate() {
+            if let Some((host, offset)) = host {
+                links.push(Link { from: *host, from_rc: false, to: r, to_rc: false, contained_at: Some(*offset), cigar: format!("{}M", reads[r].len()) });
+            }
+        }
+    }
+
+    let mut out: Vec<Vec<&Overlap>> = vec![Vec::new(); n];
+    let mut in_degree = vec![0; n];
+    for o in &overlaps {
+        out[o.from].push(o);
+        in_degree[o.to] += 1;
+    }
+    // Whether the path through r goes on to its only successor
+    let continues = |r: usize| out[r].len() == 1 && in_degree[out[r][0].to] == 1;
+    let mut visited: Vec<bool> = contained.iter().map(|c| c.is_some()).collect();
+    let mut contigs: Vec<String> = Vec::new();
+    // Paths start at reads not continuing one before them; reads left over
+    // after that are on cycles, which are cut at their lowest read
+    let mut continued = vec![false; n];
+    for r in (0..n).filter(|r| continues(*r)) {
+        continued[out[r][0].to] = true;
+    }
+    for first in (0..n).filter(|r| !continued[*r]).chain(0..n) {
+        if visited[first] {
+            continue;
+        }
+        let mut contig: Vec<u8> = reads[first].to_vec();
+        let mut r = first;
+        visited[r] = true;
+        while continues(r) && !visited[out[r][0].to] {
+            let o = out[r][0];
+            contig.extend_from_slice(&reads[o.to][o.to_len..]);
+            r = o.to;
+            visited[r] = true;
+        }
+        contigs.push(String::from_utf8_lossy(&contig).into_owned());
+    }
+    contigs
+}
+
+// Exact overlaps of at least min_olap bases, the longest for each pair of
+// reads, and for each read the read containing it (if any) with its offset
+// there. Reads are found from the min_olap bases they start with
+pub fn find_exact_overlaps(reads: &[&[u8]], min_olap: usize) -> (Vec<Overlap>, Vec<Option<(usize, usize)>>) {
+    let mut starts: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (r, read) in reads.iter().enumerate() {
+        if read.len() >= min_olap {
+            starts.entry(&read[..min_olap]).or_default().push(r);
+        }
+    }
+    let mut overlaps: Vec<Overlap> = Vec::new();
+    let mut contained: Vec<Option<(usize, usize)>> = vec![None; reads.len()];
+    for (a, read) in reads.iter().enumerate() {
+        let mut seen: HashSet<usize> = HashSet::new();
+        for pos in 0..(read.len() + 1).saturating_sub(min_olap) {
+            let Some(hits) = starts.get(&read[pos..pos + min_olap]) else {
+                continue;
+            };
+            for b in hits {
+                let b_read = reads[*b];
+                if *b == a || seen.contains(b) {
+                    continue;
+                }
+                if pos + b_read.len() <= read.len() {
+                    // Identical reads are held by the lowest id
+                    if read[pos..pos + b_read.len()] == *b_read && (b_read.len() < read.len() || *b > a) {
+                        seen.insert(*b);
+                        contained[*b].get_or_insert((a, pos));
+                    }
+                } else if pos > 0 && read[pos..] == b_read[..read.len() - pos] {
+                    seen.insert(*b);
+                    overlaps.push(Overlap { from: a, to: *b, from_start: pos, to_len: read.len() - pos, edits: 0 });
+                }
+            }
+        }
+    }
+    (overlaps, contained)
+}
+
+// Drops each overlap v -> x implied by overlaps v -> w -> x, which reach x
+// at the same offset give or take fuzz bases (Myers 2005). Exact overlaps
+// need no fuzz; approximate ones are off by up to their edits
+pub fn transitive_reduction(n: usize, overlaps: Vec<Overlap>, fuzz: usize) -> Vec<Overlap> {
+    let mut out: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for (e, o) in overlaps.iter().enumerate() {
+        out[o.from].push(e);
+    }
+    // Nearest successors first
+    for edges in &mut out {
+        edges.sort_by_key(|e| (overlaps[*e].from_start, overlaps[*e].to));
+    }
+    let mut reduce = vec![false; overlaps.len()];
+    // Offset of each successor of the read being reduced
+    let mut in_play: HashMap<usize, usize> = HashMap::new();
+    for v in 0..n {
+        in_play.clear();
+        in_play.extend(out[v].iter().map(|e| (overlaps[*e].to, *e)));
+        let Some(longest) = out[v].iter().map(|e| overlaps[*e].from_start).max() else {
+            continue;
+        };
+        for vw in &out[v] {
+            let (w, to_w) = (overlaps[*vw].to, overlaps[*vw].from_start);
+            if reduce[*vw] {
+                continue;
+            }
+            for wx in &out[w] {
+                let (x, to_x) = (overlaps[*wx].to, to_w + overlaps[*wx].from_start);
+                if to_x > longest + fuzz {
+                    break;
+                }
+                if let Some(vx) = in_play.get(&x) {
+                    if overlaps[*vx].from_start.abs_diff(to_x) <= fuzz {
+                        reduce[*vx] = true;
+                    }
+                }
+            }
+        }
+    }
+    overlaps.into_iter().zip(reduce).filter(|(_, reduce)| !reduce).map(|(o, _)| o).collect()
+}
+
+// CIGAR of read aligned to all of reference, with reference bases left
+// out of fit_align's alignment as deletions
+pub fn align_cigar(read: &[u8], reference: &[u8], band: usize) -> String {
+    let (start, ops) = fit_align(read, reference, band);
+    let aligned = ops.iter().filter(|op| **op != b'I').count();
+    let mut cigar = String::new();
+    let mut run: Option<(u8, usize)> = None;
+    let all_ops = iter::repeat_n(b'D', start)
+        .chain(ops.iter().map(|op| if *op == b'=' || *op == b'X' { b'M' } else { *op }))
+        .chain(iter::repeat_n(b'D', reference.len() - start - aligned));
+    for op in all_ops {
+        match run {
+            Some((run_op, len)) if run_op == op => run = Some((op, len + 1)),
+            _ => {
+                if let Some((run_op, len)) = run {
+                    cigar.push_str(&format!("{}{}", len, run_op as char));
+                }
+                run = Some((op, 1));
+            }
+        }
+    }
+    if let Some((run_op, len)) = run {
+        cigar.push_str(&format!("{}{}", len, run_op as char));
+    }
+    cigar
+}
+
+// Bases a CIGAR covers on the reference and on the query
+pub fn cigar_spans(cigar: &str) -> (usize, usize) {
+    let (mut on_ref, mut on_query, mut len) = (0, 0, 0);
+    for c in cigar.chars() {
+        match c {
+            '0'..='9' => len = 10 * len + c.to_digit(10).unwrap() as usize,
+            'M' | '=' | 'X' => {
+                on_ref += len;
+                on_query += len;
+                len = 0;
+            }
+            'D' | 'N' => {
+                on_ref += len;
+                len = 0;
+            }
+            _ => {
+                on_query += len;
+                len = 0;
+            }
+        }
+    }
+    (on_ref, on_query)
+}
+
+// Writes segments and links as GFA1 (S, L and C lines) or GFA2 (S and E
+// lines, with the overlapping range of each segment)
+pub fn write_gfa(path: &str, version: u32, segments: &[String], links: &[Link]) {
+    let mut write_gfa = match File::create(path) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => {
+            eprintln!("Couldn't write to file: {}", e);
+            return;
+        }
+    };
+    let strand = |rc: bool| if rc { '-' } else { '+' };
+    // GFA2 positions at the end of a segment are marked with $
+    let pos = |p: usize, len: usize| if p == len { format!("{}$", p) } else { p.to_string() };
+    let mut lines = vec![format!("H\tVN:Z:{}.0", version)];
+    for (id, seq) in segments.iter().enumerate().filter(|(_, seq)| !seq.is_empty()) {
+        lines.push(if version == 1 {
+            format!("S\t{}\t{}\tLN:i:{}", id, seq, seq.len())
+        } else {
+            format!("S\t{}\t{}\t{}", id, seq.len(), seq)
+        });
+    }
+    for link in links {
+        let (from_len, to_len) = (segments[link.from].len(), segments[link.to].len());
+        let (on_from, on_to) = cigar_spans(&link.cigar);
+        lines.push(match (version, link.contained_at) {
+            (1, Some(at)) => format!("C\t{}\t+\t{}\t+\t{}\t{}", link.from, link.to, at, link.cigar),
+            (1, None) => format!("L\t{}\t{}\t{}\t{}\t{}", link.from, strand(link.from_rc), link.to, strand(link.to_rc), link.cigar),
+            (_, Some(at)) => format!("E\t*\t{}+\t{}+\t{}\t{}\t0\t{}\t{}", link.from, link.to,
+                pos(at, from_len), pos(at + on_from, from_len), pos(to_len, to_len), link.cigar),
+            (_, None) => {
+                // The overlap is the end of from and the start of to on the
+                // strands given, so the other end on reverse strands
+                let (from_beg, from_end) = if link.from_rc { (0, on_from) } else { (from_len - on_from, from_len) };
+                let (to_beg, to_end) = if link.to_rc { (to_len - on_to, to_len) } else { (0, on_to) };
+                format!("E\t*\t{}{}\t{}{}\t{}\t{}\t{}\t{}\t{}", link.from, strand(link.from_rc), link.to, strand(link.to_rc),
+                    pos(from_beg, from_len), pos(from_end, from_len), pos(to_beg, to_len), pos(to_end, to_len), link.cigar)
+            }
+        });
+    }
+    for line in lines {
+        if let Err(e) = writeln!(write_gfa, "{}", line) {
+            eprintln!("Couldn't write to file: {}", e);
+        }
+    }
+}
+
+// Priority queue key for the overlap of from (reverse complemented if
+// from_rc) into to. Reverse complementing both sides gives the same overlap
+// as to into from with the strands flipped, so both reads reverse
+// complemented is keyed as to into from on the forward strand, and one read
+// reverse complemented is keyed with the lower id first
+pub fn overlap_key(from: u32, from_rc: bool, to: u32, to_rc: bool) -> Vec<u32> {
+    if from_rc && to_rc {
+        vec![to, from, 0, 0]
+    } else if from_rc != to_rc && to < from {
+        vec![to, from, from_rc as u32, to_rc as u32]
+    } else {
+        vec![from, to, from_rc as u32, to_rc as u32]
+    }
+}
+
+// Longest overlaps first, ties going to lower ids and forward strands
+pub fn overlap_rank(overlap: u32, key: &[u32]) -> Vec<i32> {
+    vec![overlap as i32, -(key[0] as i32), -(key[1] as i32), -((2 * key[2] + key[3]) as i32)]
+}
+
+// Root of x's set and whether x is reverse complemented relative to it
+pub fn find_oriented(uni_find: &HashMap<u32, Option<(u32, bool)>>, mut x: u32) -> (u32, bool) {
+    let mut flipped = false;
+    while let Some(Some((parent, rc))) = uni_find.get(&x) {
+        x = *parent;
+        flipped ^= rc;
+    }
+    (x, flipped)
+}
+
+pub fn reverse_complement(seq: &str) -> String {
+    seq.chars().rev().map(|c| match c {
+        'A' => 'T',
+        'C' => 'G',
+        'G' => 'C',
+        'T' => 'A',
+        other => other,
+    }).collect()
+}
+
+#[allow(clippy::needless_borrow, clippy::needless_late_init, clippy::needless_return)]
 pub fn find_overlap(curr: &str, comp: &str, min_olap: u32) -> u32 {
     let curr_suffixes = suffixes(&curr).collect::<Vec<_>>();
     let comp_prefixes = prefixes(&comp).collect::<Vec<_>>();
@@ -224,15 +1370,210 @@ pub fn find_overlap(curr: &str, comp: &str, min_olap: u32) -> u32 {
     return 0;
 }
 
+#[allow(clippy::implied_bounds_in_impls)]
 pub fn prefixes(s: &str) -> impl Iterator<Item = &str> + DoubleEndedIterator {
     s.char_indices()
         .map(move |(pos, _)| &s[..pos])
         .chain(iter::once(s))
 }
 
+#[allow(clippy::implied_bounds_in_impls)]
 pub fn suffixes(s: &str) -> impl Iterator<Item = &str> + DoubleEndedIterator {
     s.char_indices()
         .map(move |(pos, _)| &s[pos..])
         .chain(iter::once(""))
         .rev()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    fn graph(reads: &[&str], k: usize) -> DbgGraph {
+        let reads: Vec<String> = reads.iter().map(|r| r.to_string()).collect();
+        DbgGraph::new(&reads, k, 1)
+    }
+
+    // Each pair of reverse complement unitigs as the lesser of the two
+    fn unitigs(graph: &DbgGraph) -> Vec<String> {
+        let mut seqs: Vec<String> = graph.active().into_iter().map(|u| {
+            let seq = graph.unitigs[u].seq.clone();
+            cmp::min(reverse_complement(&seq), seq)
+        }).collect();
+        seqs.sort();
+        seqs.dedup();
+        seqs
+    }
+
+    #[test]
+    fn linear_path_compacts_to_one_unitig() {
+        let graph = graph(&["CCTGATAC", "TGATACGA"], 4);
+        assert_eq!(graph.active().len(), 2);
+        assert_eq!(unitigs(&graph), vec!["CCTGATACGA"]);
+        assert_eq!(graph.kmers(graph.active()[0]), 7);
+    }
+
+    #[test]
+    fn reads_from_either_strand_make_one_unitig() {
+        // The second read is the reverse complement of CCTCATCCCATTGGT
+        let graph = graph(&["AATAACCTCATC", "ACCAATGGGATGAGG"], 5);
+        assert_eq!(graph.active().len(), 2);
+        assert_eq!(unitigs(&graph), vec!["AATAACCTCATCCCATTGGT"]);
+        let (u, v) = (graph.active()[0], graph.active()[1]);
+        assert_eq!(graph.twin(u), Some(v));
+        assert_eq!(graph.coverage(u), graph.coverage(v));
+    }
+
+    #[test]
+    fn one_kmer_tip_is_clipped() {
+        // GATT branches off the path after GAT
+        let mut graph = graph(&["CCTGATACGA", "CCTGATT"], 4);
+        assert_eq!(graph.active().len(), 6);
+        assert!(graph.clip_tips(1));
+        graph.compact();
+        assert_eq!(graph.active().len(), 2);
+        assert_eq!(unitigs(&graph), vec!["CCTGATACGA"]);
+    }
+
+    #[test]
+    fn snp_bubble_keeps_the_better_covered_branch() {
+        let reference = "AATAACCTCATCCCATTGGT";
+        let snp = "AATAACCTCACCCCATTGGT";
+        for (strong, weak) in [(reference, snp), (snp, reference)] {
+            let mut graph = graph(&[strong, strong, strong, weak], 5);
+            assert_eq!(graph.active().len(), 8);
+            graph.clean(0, 10);
+            assert_eq!(unitigs(&graph), vec![strong]);
+        }
+    }
+
+    #[test]
+    fn overlap_key_is_the_same_from_either_read() {
+        assert_eq!(overlap_key(3, true, 1, true), overlap_key(1, false, 3, false));
+        assert_eq!(overlap_key(3, false, 1, true), overlap_key(1, false, 3, true));
+        assert_eq!(overlap_key(3, true, 1, false), overlap_key(1, true, 3, false));
+        assert_eq!(overlap_key(3, false, 1, true), vec![1, 3, 0, 1]);
+        assert_ne!(overlap_key(1, false, 3, true), overlap_key(1, true, 3, false));
+    }
+
+    #[test]
+    fn overlap_align_allows_a_substitution_and_an_indel() {
+        let a = b"ACGTTGCAATCGGATCCTAGGTACCATGACTTGCAGTCAA";
+        // a[20..] with C>G at 3 and the T at 12 deleted, then 10 new bases
+        let b = b"GTAGCATGACTTCAGTCAATTAGCCGATA";
+        assert_eq!(overlap_align(a, b, 20, 4), Some((20, 19, 2, false)));
+        // Contained: all of b lies inside a
+        assert_eq!(overlap_align(a, &a[10..30], 10, 4), Some((10, 20, 0, true)));
+    }
+
+    #[test]
+    fn fit_align_places_the_read_in_the_window() {
+        let window = b"ACGTTGCAATCGGATCCTAGGTACCATG";
+        let read = b"GCAATCTGATCCTAG";
+        let (start, ops) = fit_align(read, window, 3);
+        assert_eq!(start, 5);
+        assert_eq!(ops, b"======X========");
+        // One extra base in the read and one window base missing
+        let read = b"GCAAATCGGACCTAG";
+        let (start, ops) = fit_align(read, window, 3);
+        assert_eq!(start, 5);
+        assert_eq!(ops.iter().filter(|op| **op == b'I').count(), 1);
+        assert_eq!(ops.iter().filter(|op| **op == b'D').count(), 1);
+        assert_eq!(ops.len(), 16);
+    }
+
+    #[test]
+    fn consensus_outvotes_an_error_in_one_read() {
+        let truth = b"ACGTTGCAATCGGATCCTAG";
+        let error = b"ACGTTGCAATAGGATCCTAG";
+        let reads: Vec<&[u8]> = vec![error, truth, &truth[4..], &truth[..16]];
+        let layout = [(0, 0), (1, 0), (2, 4), (3, 0)];
+        assert_eq!(consensus(error, &layout, &reads, 3), "ACGTTGCAATCGGATCCTAG");
+    }
+
+    #[test]
+    fn consensus_of_an_empty_draft_is_empty() {
+        let reads: Vec<&[u8]> = vec![b""];
+        assert_eq!(consensus(b"", &[(0, 0)], &reads, 3), "");
+    }
+
+    const TRUTH: &str = "ACGTTGCAATCGGATCCTAG";
+
+    fn edges(overlaps: &[Overlap]) -> Vec<(usize, usize, usize, usize)> {
+        overlaps.iter().map(|o| (o.from, o.to, o.from_start, o.to_len)).collect()
+    }
+
+    #[test]
+    fn transitive_overlap_is_reduced() {
+        let reads: Vec<&[u8]> = vec![&TRUTH.as_bytes()[..12], &TRUTH.as_bytes()[3..15], &TRUTH.as_bytes()[6..18]];
+        let (overlaps, contained) = find_exact_overlaps(&reads, 4);
+        assert_eq!(edges(&overlaps), vec![(0, 1, 3, 9), (0, 2, 6, 6), (1, 2, 3, 9)]);
+        assert!(contained.iter().all(|c| c.is_none()));
+        assert_eq!(edges(&transitive_reduction(3, overlaps, 0)), vec![(0, 1, 3, 9), (1, 2, 3, 9)]);
+    }
+
+    #[test]
+    fn overlap_off_by_an_edit_is_reduced_with_fuzz() {
+        let overlap = |from, to, from_start| Overlap { from, to, from_start, to_len: 12 - from_start, edits: 1 };
+        let overlaps = vec![overlap(0, 1, 3), overlap(0, 2, 7), overlap(1, 2, 3)];
+        assert_eq!(transitive_reduction(3, overlaps.clone(), 0).len(), 3);
+        assert_eq!(edges(&transitive_reduction(3, overlaps, 1)), vec![(0, 1, 3, 9), (1, 2, 3, 9)]);
+    }
+
+    #[test]
+    fn identical_reads_keep_the_lowest_id() {
+        let reads: Vec<&[u8]> = vec![&TRUTH.as_bytes()[3..15], &TRUTH.as_bytes()[..12], &TRUTH.as_bytes()[3..15]];
+        let (overlaps, contained) = find_exact_overlaps(&reads, 4);
+        assert_eq!(contained, vec![None, None, Some((0, 0))]);
+        assert_eq!(edges(&overlaps), vec![(1, 0, 3, 9), (1, 2, 3, 9)]);
+    }
+
+    // Runs an assembler with its edges written to a scratch file, named
+    // after the test as tests run side by side
+    fn assemble(test: &str, reads: &[String], opts: &Options,
+                assembler: fn(&[String], &Options, &mut File, &mut Vec<Link>) -> Vec<String>) -> Vec<String> {
+        let path = env::temp_dir().join(format!("scsbler_{}_{}.edges", test, std::process::id()));
+        let mut write_edges = File::create(&path).unwrap();
+        let contigs = assembler(reads, opts, &mut write_edges, &mut Vec::new());
+        fs::remove_file(&path).unwrap();
+        contigs
+    }
+
+    #[test]
+    fn contained_read_is_left_out_of_the_contigs() {
+        let reads: Vec<String> = [&TRUTH[..12], &TRUTH[4..9], &TRUTH[3..15]].iter().map(|r| r.to_string()).collect();
+        let opts = Options { min_olap: 4, ..Default::default() };
+        let contigs = assemble("contained", &reads, &opts, assemble_string_graph);
+        assert_eq!(contigs, vec![&TRUTH[..15]]);
+    }
+
+    #[test]
+    fn olc_lays_out_reads_with_errors_as_one_contig() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let genome: Vec<u8> = (0..3000).map(|_| BASES[rng.gen_range(0..4)] as u8).collect();
+        // 20x of 100 base reads, each with a substitution or two
+        let mut starts: Vec<usize> = (0..600).map(|_| rng.gen_range(0..=2900)).collect();
+        starts.extend([0, 2900]);
+        let reads: Vec<String> = starts.iter().map(|start| {
+            let mut read = genome[*start..start + 100].to_vec();
+            for _ in 0..rng.gen_range(1..=2) {
+                let pos = rng.gen_range(0..100);
+                read[pos] = BASES.iter().map(|b| *b as u8).find(|b| *b != read[pos]).unwrap();
+            }
+            String::from_utf8(read).unwrap()
+        }).collect();
+        let args: Vec<String> = ["scsbler", "reads.fa", "30", "out", "--mode", "olc"].iter().map(|a| a.to_string()).collect();
+        let contigs = assemble("olc_errors", &reads, &parse_args(&args), assemble_olc);
+        assert_eq!(contigs, vec![String::from_utf8(genome).unwrap()]);
+    }
+
+    #[test]
+    fn olc_skips_empty_reads() {
+        let args: Vec<String> = ["scsbler", "reads.fa", "4", "out", "--mode", "olc", "--seed-len", "4"].iter().map(|a| a.to_string()).collect();
+        let reads = vec![TRUTH[..12].to_string(), String::new(), TRUTH[3..15].to_string()];
+        assert_eq!(assemble("olc_empty", &reads, &parse_args(&args), assemble_olc), vec![&TRUTH[..15]]);
+        assert!(assemble("olc_no_reads", &[String::new()], &parse_args(&args), assemble_olc).is_empty());
+    }
+}